@@ -0,0 +1,145 @@
+// 同步几千个块的基准测试：cargo bench --bench sync
+// 先在内存里挖好一条链，再计时把它同步到一条新链上，并对比主链上缓存的块哈希和每次都重新计算的开销
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/block.rs"]
+mod block;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/cryptography.rs"]
+mod cryptography;
+#[path = "../src/difficulty.rs"]
+mod difficulty;
+#[path = "../src/genesis.rs"]
+mod genesis;
+#[path = "../src/ledger.rs"]
+mod ledger;
+#[path = "../src/protocol/mod.rs"]
+mod protocol;
+#[path = "../src/storage.rs"]
+mod storage;
+#[path = "../src/transaction.rs"]
+mod transaction;
+#[path = "../src/utxo.rs"]
+mod utxo;
+
+use block::{Block, Chain, Coinbase, Hash};
+use ed25519_dalek::{PublicKey, SecretKey};
+use genesis::GenesisSpec;
+use protocol::TARGET_BLOCK_TIME_MS;
+use std::time::{Duration, Instant};
+
+const BLOCKS: usize = 5000;
+const ROUNDS: usize = 20;
+
+fn main() {
+    // 难度上限很低的测试网，挖块几乎不花时间
+    let spec = GenesisSpec {
+        pow_limit_bits: 0x207f_ffff,
+        ..GenesisSpec::runchainnet()
+    };
+    let miner = PublicKey::from(&SecretKey::from_bytes(&[1; 32]).unwrap());
+    let source = mine_chain(&spec, &miner, BLOCKS);
+    println!("⛏️已挖好{}个块", BLOCKS);
+
+    let start = Instant::now();
+    let mut synced = Chain::from_spec(&spec).unwrap();
+    for block in source.main_chain().iter().skip(1) {
+        synced.try_add_a_block(block.clone()).unwrap();
+    }
+    let elapsed = start.elapsed();
+    report("同步", BLOCKS, elapsed);
+
+    // 同步时每来一个块都要拿链尾和创世块的哈希，以前每次都重新算
+    let cached = time(|| {
+        let mut acc = 0u8;
+        for _ in 0..BLOCKS {
+            acc ^= synced.tip_hash()[0] ^ synced.genesis_hash()[0];
+        }
+        acc
+    });
+    let recomputed = time(|| {
+        let mut acc = 0u8;
+        for _ in 0..BLOCKS {
+            acc ^= synced.last_block().hash()[0] ^ synced.main_chain()[0].hash()[0];
+        }
+        acc
+    });
+    report("缓存的链尾+创世块哈希", BLOCKS, cached);
+    report("重新计算链尾+创世块哈希", BLOCKS, recomputed);
+
+    // 按previous_hash找父块：缓存的索引 vs 沿主链边算哈希边找
+    let parents: Vec<Hash> = synced
+        .main_chain()
+        .iter()
+        .step_by(BLOCKS / 100)
+        .map(|n| n.previous_hash)
+        .skip(1)
+        .collect();
+    let indexed = time(|| {
+        parents
+            .iter()
+            .filter(|n| synced.get_by_hash(n).is_some())
+            .count() as u8
+    });
+    let scanned = time(|| {
+        parents
+            .iter()
+            .filter(|n| synced.main_chain().iter().any(|b| b.hash() == **n))
+            .count() as u8
+    });
+    report("按索引找父块", parents.len(), indexed);
+    report("扫描主链找父块", parents.len(), scanned);
+}
+
+// 在新链上接连挖n个空块，每个块比前一个晚TARGET_BLOCK_TIME_MS
+fn mine_chain(spec: &GenesisSpec, miner: &PublicKey, n: usize) -> Chain {
+    let mut chain = Chain::from_spec(spec).unwrap();
+    for _ in 0..n {
+        let parent = chain.last_block().clone();
+        let height = parent.height + 1;
+        let mut block = Block {
+            height,
+            previous_hash: chain.tip_hash(),
+            timestamp: parent.timestamp + TARGET_BLOCK_TIME_MS,
+            merkle_root: [0; 32],
+            bits: chain.next_bits(&parent),
+            nonce: 0,
+            coinbase: Coinbase {
+                public_key: miner.to_bytes().to_vec(),
+                reward: chain.block_reward(height),
+            },
+            upinfo: vec![],
+            transactions: vec![],
+            utxo_transactions: vec![],
+        };
+        block.merkle_root = block.compute_merkle_root();
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        chain.try_add_a_block(block).unwrap();
+    }
+    chain
+}
+
+// 跑ROUNDS轮取最快的一轮
+fn time(mut f: impl FnMut() -> u8) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn report(name: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{}: {}次共{:?}，平均每次{:?}",
+        name,
+        count,
+        elapsed,
+        elapsed / count as u32
+    );
+}
@@ -0,0 +1,172 @@
+// 链的导出文件，有两种格式：
+//      json  每行一个块(JSON Lines)，方便在不同节点之间diff
+//      bin   8字节的BIN_MAGIC，后面跟着和blocks.dat相同格式的记录(见storage.rs)
+// 两种格式都是一个块接一个块顺序写的，可以边读边处理。读的时候根据文件开头自动识别格式
+use crate::block::Block;
+use crate::storage::{encode_record, read_record};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const BIN_MAGIC: &[u8; 8] = b"RCHAIN01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Json,
+    Bin,
+}
+
+impl ArchiveFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "json" => Some(ArchiveFormat::Json),
+            "bin" => Some(ArchiveFormat::Bin),
+            _ => None,
+        }
+    }
+}
+
+// 返回写入的块数
+pub fn write_blocks<'a>(
+    path: impl AsRef<Path>,
+    format: ArchiveFormat,
+    blocks: impl IntoIterator<Item = &'a Block>,
+) -> io::Result<usize> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    if format == ArchiveFormat::Bin {
+        writer.write_all(BIN_MAGIC)?;
+    }
+    let mut count = 0;
+    for block in blocks {
+        match format {
+            ArchiveFormat::Json => {
+                serde_json::to_writer(&mut writer, block)?;
+                writer.write_all(b"\n")?;
+            }
+            ArchiveFormat::Bin => writer.write_all(&encode_record(block)?)?,
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// 打开导出文件，返回的迭代器边读边解析，一次只在内存中放一个块。遇到坏记录时产生一个错误，然后结束
+pub fn open_blocks(path: impl AsRef<Path>) -> io::Result<BlockReader> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let format = if reader.fill_buf()?.starts_with(BIN_MAGIC) {
+        reader.consume(BIN_MAGIC.len());
+        ArchiveFormat::Bin
+    } else {
+        ArchiveFormat::Json
+    };
+    Ok(BlockReader {
+        reader,
+        format,
+        count: 0,
+        line: 0,
+        done: false,
+    })
+}
+
+pub struct BlockReader {
+    reader: BufReader<File>,
+    format: ArchiveFormat,
+    count: usize, // 已经读出的块数
+    line: usize,  // json格式的当前行号
+    done: bool,
+}
+
+impl BlockReader {
+    fn read_bin(&mut self) -> io::Result<Option<Block>> {
+        // 和blocks.dat不同，导出文件不做截断恢复，有坏记录就直接报错
+        read_record(&mut self.reader).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted record #{}: {}", self.count + 1, e),
+            )
+        })
+    }
+
+    fn read_json(&mut self) -> io::Result<Option<Block>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let block = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", self.line, e),
+            )
+        })?;
+        Ok(Some(block))
+    }
+}
+
+impl Iterator for BlockReader {
+    type Item = io::Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.format {
+            ArchiveFormat::Json => self.read_json(),
+            ArchiveFormat::Bin => self.read_bin(),
+        };
+        match result {
+            Ok(Some(block)) => {
+                self.count += 1;
+                Some(Ok(block))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::*;
+
+    #[test]
+    fn both_formats_read_back_block_by_block() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        extend(&mut chain, 3, &keypair(1));
+        let dir = temp_dir("archive");
+        std::fs::create_dir_all(&dir).unwrap();
+        for format in [ArchiveFormat::Json, ArchiveFormat::Bin] {
+            let path = dir.join("chain.export");
+            assert_eq!(write_blocks(&path, format, chain.main_chain()).unwrap(), 4);
+            let hashes: Vec<_> = open_blocks(&path)
+                .unwrap()
+                .map(|n| n.unwrap().hash())
+                .collect();
+            let expected: Vec<_> = chain.main_chain().iter().map(Block::hash).collect();
+            assert_eq!(hashes, expected);
+
+            // 截掉最后几个字节，前面完好的块照常读出，最后产生一个错误
+            let data = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &data[..data.len() - 3]).unwrap();
+            let read: Vec<_> = open_blocks(&path).unwrap().collect();
+            assert_eq!(read.len(), 4);
+            assert!(read[..3].iter().all(Result::is_ok));
+            assert!(read[3].is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
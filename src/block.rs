@@ -1,55 +1,161 @@
 // block.rs是个模块，它里面不能写mod。它只能写use。并且它use的模块必须被所有bin文件都mod进，不然就等于没有被纳入编译树
-use crate::protocol::DIFFICULTY_PREFIX;
-use rs_merkle::{algorithms::Sha256, Hasher, MerkleTree};
+use crate::difficulty;
+use crate::protocol::{
+    MAX_BLOCK_BODY_BYTES, MAX_BLOCK_ENTRIES, MAX_FUTURE_DRIFT_MS, MEDIAN_TIME_SPAN,
+    RETARGET_INTERVAL, TARGET_BLOCK_TIME_MS, UPINFO_MAX_LIFETIME,
+};
+use rs_merkle::{algorithms::Sha256, Hasher, MerkleProof, MerkleTree};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256 as sha2_sha256};
 // 放一些block相关的数据结构和逻辑函数
-type Hash = String;
-use chrono::prelude::*;
-use std::io::Error;
-type Timestamp = String;
-use crate::pow;
+pub type Hash = [u8; 32];
+type Timestamp = i64; // UNIX时间戳，单位毫秒
+use crate::clock::{Clock, SystemClock};
+use crate::cryptography::{self, ChainId, MessageTag};
+use crate::genesis::{GenesisSpec, Issuance};
+use crate::ledger::{Ledger, LedgerKind, TransactionError};
+use crate::storage::BlockStore;
+use crate::transaction::Transaction;
+use crate::utxo::{UtxoSnapshot, UtxoTransaction};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 
-#[derive(Clone)]
+// 链实际上是一棵块树：blocks是累计工作量最大的主链，其他分叉上的块放在side_blocks中。
+// 某个分叉的累计工作量超过主链时就发生重组，把它换成主链
 pub struct Chain {
-    blocks: Vec<Block>,
+    blocks: Vec<Block>,                // 主链，下标就是块高度
+    hashes: Vec<Hash>,                 // 主链上每个块的哈希，和blocks一一对应，省得每次都重新算
+    main_index: HashMap<Hash, usize>,  // 主链块的哈希到高度的索引
+    side_blocks: HashMap<Hash, Block>, // 不在主链上的分叉块
+    total_work: HashMap<Hash, u128>,   // 每个已知块从创世块开始累计的工作量
+    store: Option<BlockStore>,         // 为None时链只存在于内存中
+    clock: Box<dyn Clock>,             // 验证时间戳时用到的本地时间
+    issuance: Issuance,                // 验证coinbase中的奖励
+    ledger: Ledger,                    // 主链末尾处的账本
+}
+
+// 接入一个块时做哪些检查
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Validation {
+    New,     // 新块，检查全部规则
+    Replay,  // 以前接受过的块，不检查未来时间的规则
+    Trusted, // 只读加载自己写盘的块，不验证，只执行账本
+}
+
+// try_add_a_block成功时告诉调用者块被放到了哪里
+#[derive(Debug)]
+pub enum AddBlockOutcome {
+    Extended,   // 接在了主链末尾
+    SideBranch, // 放进了分叉，主链不变
+    // 分叉变成了主链。disconnected是从主链上撤下来的块，connected是新接到主链上的块，都按高度从低到高排列
+    Reorganized {
+        disconnected: Vec<Block>,
+        connected: Vec<Block>,
+    },
 }
 
 // &[[u8;32]]
 impl Chain {
-    pub fn new() -> Self {
-        let first = [Sha256::hash("This is RunChain's first block".as_bytes())];
-        let merkle_tree = MerkleTree::<Sha256>::from_leaves(&first);
-
-        let merkle_root = match merkle_tree.root() {
-            Some(value) => value,
-            None => [
-                202, 151, 129, 18, 202, 27, 189, 202, 250, 194, 49, 179, 154, 35, 220, 77, 167,
-                134, 239, 248, 20, 124, 78, 114, 185, 128, 119, 133, 175, 238, 72, 187,
-            ],
-        };
+    // 创世块、发行计划和账本模型都来自GenesisSpec，见genesis.rs。clock是验证时间戳时使用的本地时间来源
+    pub fn new(
+        genesis_block: Block,
+        issuance: Issuance,
+        ledger_kind: LedgerKind,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        let genesis_hash = genesis_block.hash();
+        let mut total_work = HashMap::new();
+        total_work.insert(genesis_hash, block_work(&genesis_block));
+        Chain {
+            blocks: vec![genesis_block],
+            hashes: vec![genesis_hash],
+            main_index: HashMap::from([(genesis_hash, 0)]),
+            side_blocks: HashMap::new(),
+            total_work,
+            store: None,
+            clock,
+            issuance,
+            ledger: Ledger::new(ledger_kind, genesis_hash),
+        }
+    }
 
-        let merkle_root: [u8; 32] = merkle_root;
+    // 从data_dir中加载链，加载时每个块都会重新验证一遍。目录为空时新建创世块并写盘
+    pub fn open(data_dir: impl AsRef<Path>, genesis: &GenesisSpec) -> io::Result<Self> {
+        fs::create_dir_all(&data_dir)?;
+        let (mut store, stored_blocks) = BlockStore::open(data_dir.as_ref().join("blocks.dat"))?;
+        let mut stored_blocks = stored_blocks.into_iter();
 
-        let upinfo = vec![
-            format!("{}{}", "Tonight,you are so beautiful. ", Utc::now()),
-            format!("{}{}", "I want you more than any other time. ", Utc::now()),
-        ];
+        let mut chain = Chain::from_spec(genesis)?;
+        match stored_blocks.next() {
+            Some(stored_genesis) => chain.check_stored_genesis(&stored_genesis)?,
+            None => store.append(chain.last_block())?,
+        }
 
-        let genesis_block = Block {
-            height: 0,
-            previous_hash: vec![
-                202, 151, 129, 18, 202, 27, 189, 202, 250, 194, 49, 179, 154, 35, 220, 77, 167,
-                134, 239, 248, 20, 124, 78, 114, 185, 128, 119, 133, 175, 238, 72, 187,
-            ],
-            timestamp: Utc::now().to_string(),
-            merkle_root,
-            nonce: 0,
-            upinfo,
-        };
-        Chain {
-            blocks: vec![genesis_block],
+        // 块文件中分叉块和主链块按收到的顺序混在一起，按顺序重放一遍就能恢复出同样的块树。
+        // 残缺的尾巴BlockStore::open已经截掉了，剩下的都是完整的记录，通不过验证说明数据有问题，
+        // 报错让用户用verify命令检查，不能悄悄删掉
+        for block in stored_blocks {
+            let height = block.height;
+            if let Err(e) = chain.replay_block(block) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("stored block at height {} failed validation: {}", height, e),
+                ));
+            }
+        }
+
+        println!(
+            "📦从磁盘加载了{}个主链块，{}个分叉块",
+            chain.block_height(),
+            chain.side_blocks.len()
+        );
+        chain.store = Some(store);
+        Ok(chain)
+    }
+
+    // 只读地加载data_dir中的链：不创建、不截断块文件，也不重新验证块(它们写盘之前已经验证过了)，只执行账本。
+    // export、prove等只读的命令用它。接不上的块报告出来，连同它之后的块一起忽略
+    pub fn open_read_only(data_dir: impl AsRef<Path>, genesis: &GenesisSpec) -> io::Result<Self> {
+        let mut stored_blocks =
+            BlockStore::read_all(data_dir.as_ref().join("blocks.dat"))?.into_iter();
+        let mut chain = Chain::from_spec(genesis)?;
+        if let Some(stored_genesis) = stored_blocks.next() {
+            chain.check_stored_genesis(&stored_genesis)?;
+        }
+        for block in stored_blocks {
+            let height = block.height;
+            if let Err(e) = chain.add_block(block, Validation::Trusted) {
+                println!(
+                    "⚠️磁盘上高度为{}的块接不上:{}，忽略它及其之后的块",
+                    height, e
+                );
+                break;
+            }
+        }
+        Ok(chain)
+    }
+
+    // 内存中的新链，只有genesis的创世块，使用本地时间
+    pub fn from_spec(genesis: &GenesisSpec) -> io::Result<Self> {
+        Ok(Chain::new(
+            genesis.to_block()?,
+            genesis.issuance,
+            genesis.ledger,
+            Box::new(SystemClock),
+        ))
+    }
+
+    fn check_stored_genesis(&self, stored_genesis: &Block) -> io::Result<()> {
+        if stored_genesis.hash() != self.genesis_hash() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the data dir holds a chain with a different genesis block",
+            ));
         }
+        Ok(())
     }
 
     pub fn show_chain(&self) {
@@ -58,74 +164,1624 @@ impl Chain {
         }
     }
 
-    pub fn genesis_hash(&self) -> Vec<u8> {
-        let genesis_block = self.blocks.first().unwrap();
-        self.calculate_hash(genesis_block).unwrap()
+    pub fn genesis_hash(&self) -> Hash {
+        self.hashes[0]
+    }
+
+    // 签名时用的链标识，见cryptography.rs
+    pub fn chain_id(&self) -> ChainId {
+        self.genesis_hash()
+    }
+
+    pub fn tip_hash(&self) -> Hash {
+        *self.hashes.last().unwrap()
     }
     pub fn block_height(&self) -> usize {
         self.blocks.len()
     }
 
-    pub fn try_add_a_block(&mut self, block: Block) -> Result<(), &str> {
-        if self.is_block_vaild(&block) {
-            self.blocks.push(block);
-            return Ok(());
+    pub fn main_chain(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    // 把blocks按顺序接到这条链上，每个块都重新验证(未来时间的规则除外，见replay_block)。
+    // blocks的顺序和块文件中一样，分叉块和主链块可以混在一起；可以从创世块开始，和本链的创世块不一致时报错。
+    // 返回接上的块数，或者第一个验证失败的块的高度和原因。verify在Chain::from_spec得到的新链上用它检查本地的块文件
+    pub fn verify_all(
+        &mut self,
+        blocks: impl IntoIterator<Item = Block>,
+    ) -> Result<usize, (usize, BlockValidationError)> {
+        self.add_all(blocks, Validation::Replay)
+    }
+
+    // 和verify_all一样，但blocks来自别处(比如import的导出文件)，当作新块检查全部规则
+    pub fn verify_new(
+        &mut self,
+        blocks: impl IntoIterator<Item = Block>,
+    ) -> Result<usize, (usize, BlockValidationError)> {
+        self.add_all(blocks, Validation::New)
+    }
+
+    fn add_all(
+        &mut self,
+        blocks: impl IntoIterator<Item = Block>,
+        validation: Validation,
+    ) -> Result<usize, (usize, BlockValidationError)> {
+        let mut count = 0;
+        for block in blocks {
+            let height = block.height;
+            if height == 0 {
+                if block.hash() != self.genesis_hash() {
+                    return Err((0, BlockValidationError::GenesisMismatch));
+                }
+                continue;
+            }
+            match self.add_block(block, validation) {
+                Ok(_) => count += 1,
+                Err(BlockValidationError::AlreadyKnown) => {}
+                Err(e) => return Err((height, e)),
+            }
         }
-        Err("add a block error because it is not a vaild block")
+        Ok(count)
     }
 
-    pub fn last_block(&self) -> &Block {
-        self.blocks.last().unwrap()
+    // 接在主链末尾、高度为height的块的出块奖励
+    pub fn block_reward(&self, height: usize) -> u64 {
+        self.issuance.reward_at(height)
     }
 
-    pub fn last_n_blocks(&self, n: usize) -> Vec<Block> {
-        assert!(n <= self.blocks.len());
+    // 主链上由public_key挖出的块的高度
+    pub fn blocks_mined_by(&self, public_key: &[u8]) -> Vec<usize> {
         self.blocks
-            .clone()
-            .into_iter()
-            .skip(self.blocks.len() - n)
+            .iter()
+            .filter(|n| n.coinbase.public_key == public_key)
+            .map(|n| n.height)
             .collect()
     }
 
-    pub fn is_block_vaild(&self, block: &Block) -> bool {
-        let previous_block = self.last_block();
-        let previous_block_hash = self.calculate_hash(previous_block).unwrap();
-        if block.previous_hash != previous_block_hash {
-            println!("block with height:{} has wrong previous hash", block.height);
-            return false;
+    // 主链末尾处的余额
+    pub fn balance(&self, public_key: &[u8]) -> u64 {
+        self.ledger.balance(public_key)
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    // 主链上高度为height的块之后的UTXO集合快照，账本不是UTXO模型时返回None
+    pub fn utxo_snapshot_at(&self, height: usize) -> Option<UtxoSnapshot> {
+        if height >= self.blocks.len() {
+            return None;
+        }
+        let mut ledger = self.ledger.clone();
+        for block in self.blocks[height + 1..].iter().rev() {
+            ledger.rollback_block(block);
+        }
+        match ledger {
+            Ledger::Utxo(ledger) => Some(ledger.snapshot(height, self.hashes[height])),
+            Ledger::Account(_) => None,
         }
+    }
 
-        if &self.calculate_hash(block).unwrap()[..DIFFICULTY_PREFIX.len()] != DIFFICULTY_PREFIX {
-            println!("block with height: {} has invalid difficulty", block.height);
-            return false;
+    pub fn tip_work(&self) -> u128 {
+        self.total_work[&self.tip_hash()]
+    }
+
+    // 收到别人发来的或者自己挖出的新块。除了is_block_vaild中的规则，时间戳还不能比本地时间快MAX_FUTURE_DRIFT_MS以上
+    pub fn try_add_a_block(
+        &mut self,
+        block: Block,
+    ) -> Result<AddBlockOutcome, BlockValidationError> {
+        self.add_block(block, Validation::New)
+    }
+
+    // 重放以前已经接受过的块，比如启动时加载块文件、验证整条链。这些块当初已经检查过未来时间的规则，
+    // 本地时钟后来被往回拨(NTP校时、虚拟机恢复快照)不应该让它们失效，所以不再检查这一条
+    pub fn replay_block(&mut self, block: Block) -> Result<AddBlockOutcome, BlockValidationError> {
+        self.add_block(block, Validation::Replay)
+    }
+
+    fn add_block(
+        &mut self,
+        block: Block,
+        validation: Validation,
+    ) -> Result<AddBlockOutcome, BlockValidationError> {
+        let hash = block.hash();
+        if self.total_work.contains_key(&hash) {
+            return Err(BlockValidationError::AlreadyKnown);
+        }
+        let parent_work = *self
+            .total_work
+            .get(&block.previous_hash)
+            .ok_or(BlockValidationError::UnknownParent)?;
+        if validation != Validation::Trusted {
+            self.is_block_vaild(&block, &hash)?;
+        }
+        if validation == Validation::New {
+            let now = self.clock.now_millis();
+            if block.timestamp > now + MAX_FUTURE_DRIFT_MS {
+                return Err(BlockValidationError::TimestampTooFarInFuture {
+                    timestamp: block.timestamp,
+                    now,
+                });
+            }
         }
 
-        if block.height != previous_block.height + 1 {
-            println!("block with height: {} has invalid height", block.height);
-            return false;
+        // 在父块处的账户状态上执行块中的交易。接在主链末尾时直接改self.ledger，否则先算出分叉上父块处的账本
+        let extends_tip = block.previous_hash == self.tip_hash();
+        let mut branch_ledger = None;
+        if extends_tip {
+            self.ledger.apply_block(&block)?;
+        } else {
+            let mut ledger = self.ledger_at(&block.previous_hash);
+            ledger.apply_block(&block)?;
+            branch_ledger = Some(ledger);
         }
 
-        true
+        // 先落盘再加入内存，保证内存中的链永远不比磁盘上的长
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(&block) {
+                if extends_tip {
+                    self.ledger.rollback_block(&block);
+                }
+                return Err(BlockValidationError::Storage(e.to_string()));
+            }
+        }
+
+        // 难度不合理的块工作量可能接近u128::MAX，累加时不能溢出
+        let work = parent_work.saturating_add(block_work(&block));
+        let tip_work = self.tip_work();
+        self.total_work.insert(hash, work);
+
+        if extends_tip {
+            self.main_index.insert(hash, block.height);
+            self.hashes.push(hash);
+            self.blocks.push(block);
+            return Ok(AddBlockOutcome::Extended);
+        }
+
+        self.side_blocks.insert(hash, block);
+        if work > tip_work {
+            return Ok(self.reorganize(hash, branch_ledger.unwrap()));
+        }
+        Ok(AddBlockOutcome::SideBranch)
     }
 
-    pub fn calculate_hash(&self, block: &Block) -> Result<Vec<u8>, Error> {
-        let mut hasher = sha2_sha256::new();
-        let s = format!(
-            "{}{:?}{}{:?}{}",
-            block.height, block.previous_hash, block.timestamp, block.merkle_root, block.nonce
+    // 分叉上hash这个块之后的账户状态：从主链末尾的账本出发，撤回分叉点之后的主链块，再依次执行分叉上的块
+    fn ledger_at(&self, hash: &Hash) -> Ledger {
+        let mut branch = vec![];
+        let mut cursor = *hash;
+        while let Some(block) = self.side_blocks.get(&cursor) {
+            branch.push(block);
+            cursor = block.previous_hash;
+        }
+
+        let fork_height = self.main_index[&cursor];
+        let mut ledger = self.ledger.clone();
+        for block in self.blocks[fork_height + 1..].iter().rev() {
+            ledger.rollback_block(block);
+        }
+        for block in branch.into_iter().rev() {
+            ledger
+                .apply_block(block)
+                .expect("side blocks were checked against the ledger when they were added");
+        }
+        ledger
+    }
+
+    // 把以new_tip结尾的分叉换成主链，ledger是new_tip处的账户状态
+    fn reorganize(&mut self, new_tip: Hash, ledger: Ledger) -> AddBlockOutcome {
+        // 从新的链尾沿着previous_hash往回走，直到走回主链上，得到分叉点
+        let mut connected = vec![];
+        let mut connected_hashes = vec![];
+        let mut cursor = new_tip;
+        while let Some(block) = self.side_blocks.remove(&cursor) {
+            connected_hashes.push(cursor);
+            cursor = block.previous_hash;
+            connected.push(block);
+        }
+        connected.reverse();
+        connected_hashes.reverse();
+
+        let fork_height = connected[0].height - 1;
+        let disconnected = self.blocks.split_off(fork_height + 1);
+        let disconnected_hashes = self.hashes.split_off(fork_height + 1);
+        for (block, hash) in disconnected.iter().zip(disconnected_hashes) {
+            self.main_index.remove(&hash);
+            self.side_blocks.insert(hash, block.clone());
+        }
+        for (block, hash) in connected.iter().zip(connected_hashes.iter()) {
+            self.main_index.insert(*hash, block.height);
+        }
+        self.blocks.extend(connected.iter().cloned());
+        self.hashes.extend(connected_hashes);
+        self.ledger = ledger;
+
+        println!(
+            "🔀发生重组，分叉点高度{}，撤下{}个块，接上{}个块",
+            fork_height,
+            disconnected.len(),
+            connected.len()
         );
-        let s = s.as_bytes();
-        hasher.update(s);
-        Ok(hasher.finalize().to_vec())
+        AddBlockOutcome::Reorganized {
+            disconnected,
+            connected,
+        }
+    }
+
+    // 接在parent后面的块应该使用的难度目标。每RETARGET_INTERVAL个块根据这段时间的实际出块用时调整一次，其余时候沿用父块的
+    pub fn next_bits(&self, parent: &Block) -> u32 {
+        let height = parent.height + 1;
+        if !height.is_multiple_of(RETARGET_INTERVAL) {
+            return parent.bits;
+        }
+        // parent和first之间隔了RETARGET_INTERVAL-1个出块间隔
+        let first = self.ancestor(parent, height - RETARGET_INTERVAL).unwrap();
+        let actual_timespan = parent.timestamp - first.timestamp;
+        let expected_timespan = (RETARGET_INTERVAL as i64 - 1) * TARGET_BLOCK_TIME_MS;
+        // 创世块的难度就是这条链的难度上限
+        difficulty::retarget(
+            parent.bits,
+            actual_timespan,
+            expected_timespan,
+            self.blocks[0].bits,
+        )
+    }
+
+    // parent及其之前共MEDIAN_TIME_SPAN个块时间戳的中位数，接在parent后面的块的时间戳必须比它大
+    pub fn median_time_past(&self, parent: &Block) -> i64 {
+        let first_height = (parent.height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps: Vec<i64> = (first_height..=parent.height)
+            .map(|height| self.ancestor(parent, height).unwrap().timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    // block所在的链上高度为height的祖先块(可以是block自己)
+    fn ancestor<'a>(&'a self, block: &'a Block, height: usize) -> Option<&'a Block> {
+        let mut cursor = block;
+        while cursor.height > height {
+            // 沿着分叉往回走，一旦走回主链就可以直接按高度取
+            if !self.side_blocks.contains_key(&cursor.previous_hash) {
+                return self.get_by_height(height);
+            }
+            cursor = &self.side_blocks[&cursor.previous_hash];
+        }
+        if cursor.height == height {
+            Some(cursor)
+        } else {
+            None
+        }
+    }
+
+    // parent所在的链上最近UPINFO_MAX_LIFETIME个块里upinfo的replay_id。
+    // 一条upinfo只能在过期前UPINFO_MAX_LIFETIME个块之内上链，所以和parent的子块重复的upinfo只可能在这些块里
+    pub fn recent_entries(&self, parent: &Block) -> HashSet<Hash> {
+        let first_height = (parent.height + 1).saturating_sub(UPINFO_MAX_LIFETIME);
+        (first_height..=parent.height)
+            .flat_map(|height| self.ancestor(parent, height).unwrap().upinfo.iter())
+            .map(|n| n.replay_id())
+            .collect()
+    }
+
+    // 主链上高度为height的块
+    pub fn get_by_height(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height)
+    }
+
+    // 按哈希在主链和分叉中找块，返回的块不一定在主链上
+    pub fn get_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        match self.main_index.get(hash) {
+            Some(height) => Some(&self.blocks[*height]),
+            None => self.side_blocks.get(hash),
+        }
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.main_index.contains_key(hash) || self.side_blocks.contains_key(hash)
+    }
+
+    // 主链上高度从from到to(包含)的块，范围不合法时返回None
+    pub fn range(&self, from: usize, to: usize) -> Option<&[Block]> {
+        if from > to {
+            return None;
+        }
+        self.blocks.get(from..=to)
+    }
+
+    // block_hash块中第index条upinfo的默克尔包含证明，块或者upinfo不存在时返回None
+    pub fn inclusion_proof(&self, block_hash: &Hash, index: usize) -> Option<InclusionProof> {
+        let block = self.get_by_hash(block_hash)?;
+        let entry = block.upinfo.get(index)?.clone();
+        let leaves = merkle_leaves(block);
+        // 0号叶子是coinbase
+        let proof = MerkleTree::<Sha256>::from_leaves(&leaves).proof(&[index + 1]);
+        Some(InclusionProof {
+            block_hash: *block_hash,
+            index: index + 1,
+            total_leaves: leaves.len(),
+            entry,
+            proof: proof.to_bytes(),
+        })
+    }
+
+    // 按upinfo的内容找，块中有多条相同内容时用第一条
+    pub fn inclusion_proof_of(&self, block_hash: &Hash, upinfo: &str) -> Option<InclusionProof> {
+        let index = self
+            .get_by_hash(block_hash)?
+            .upinfo
+            .iter()
+            .position(|n| n.upinfo == upinfo)?;
+        self.inclusion_proof(block_hash, index)
+    }
+
+    pub fn last_block(&self) -> &Block {
+        self.blocks.last().unwrap()
+    }
+
+    // 主链末尾的n个块，不够n个时返回整条主链
+    pub fn last_n_blocks(&self, n: usize) -> &[Block] {
+        &self.blocks[self.blocks.len().saturating_sub(n)..]
+    }
+
+    // 块不一定接在主链末尾，所以要和它在块树中的父块比较
+    // hash是调用者已经算好的block的哈希，省得再算一遍。和本地时间有关的规则不在这里检查，见try_add_a_block
+    pub fn is_block_vaild(&self, block: &Block, hash: &Hash) -> Result<(), BlockValidationError> {
+        let previous_block = self
+            .get_by_hash(&block.previous_hash)
+            .ok_or(BlockValidationError::UnknownParent)?;
+
+        if block.height != previous_block.height + 1 {
+            return Err(BlockValidationError::BadHeight {
+                expected: previous_block.height + 1,
+                got: block.height,
+            });
+        }
+
+        let expected_bits = self.next_bits(previous_block);
+        if block.bits != expected_bits {
+            return Err(BlockValidationError::UnexpectedTarget {
+                expected: expected_bits,
+                got: block.bits,
+            });
+        }
+
+        if !difficulty::hash_meets_target(hash, block.bits) {
+            return Err(BlockValidationError::BadProofOfWork);
+        }
+
+        let median_time_past = self.median_time_past(previous_block);
+        if block.timestamp <= median_time_past {
+            return Err(BlockValidationError::TimestampTooOld {
+                timestamp: block.timestamp,
+                median_time_past,
+            });
+        }
+
+        let expected_reward = self.issuance.reward_at(block.height);
+        if block.coinbase.reward != expected_reward {
+            return Err(BlockValidationError::BadCoinbaseReward {
+                expected: expected_reward,
+                got: block.coinbase.reward,
+            });
+        }
+
+        if !cryptography::is_valid_public_key(&block.coinbase.public_key) {
+            return Err(BlockValidationError::BadCoinbaseKey);
+        }
+
+        let entries = block.upinfo.len() + block.transactions.len() + block.utxo_transactions.len();
+        if entries > MAX_BLOCK_ENTRIES {
+            return Err(BlockValidationError::TooManyEntries(entries));
+        }
+
+        if block.body_size() > MAX_BLOCK_BODY_BYTES {
+            return Err(BlockValidationError::BodyTooLarge(block.body_size()));
+        }
+
+        let chain_id = self.chain_id();
+        if let Some(index) = block.upinfo.iter().position(|n| !n.verify(&chain_id)) {
+            return Err(BlockValidationError::BadSignature { index });
+        }
+
+        // 重放保护：upinfo必须在有效期内，并且没有在这条链上出现过
+        let mut seen = self.recent_entries(previous_block);
+        for (index, entry) in block.upinfo.iter().enumerate() {
+            if !entry.is_live_at(block.height) {
+                return Err(BlockValidationError::ExpiredEntry { index });
+            }
+            if !seen.insert(entry.replay_id()) {
+                return Err(BlockValidationError::DuplicateEntry { index });
+            }
+        }
+
+        // 交易的nonce和余额要等到执行时才能检查，这里只验签名
+        if let Some(index) = block.transactions.iter().position(|n| !n.verify(&chain_id)) {
+            return Err(BlockValidationError::BadTransaction {
+                index,
+                reason: TransactionError::BadSignature,
+            });
+        }
+
+        if block.compute_merkle_root() != block.merkle_root {
+            return Err(BlockValidationError::BadMerkleRoot);
+        }
+
+        Ok(())
+    }
+}
+
+// 块没能加入链的原因。调用者可以据此做不同的处理，比如UnknownParent时去请求缺失的祖先块，
+// BadProofOfWork时惩罚发来这个块的节点
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    AlreadyKnown,
+    GenesisMismatch, // 要验证的链和本网络的创世块不一致
+    UnknownParent,
+    BadHeight {
+        expected: usize,
+        got: usize,
+    },
+    UnexpectedTarget {
+        expected: u32,
+        got: u32,
+    },
+    BadProofOfWork,
+    TimestampTooOld {
+        timestamp: i64,
+        median_time_past: i64,
+    },
+    TimestampTooFarInFuture {
+        timestamp: i64,
+        now: i64,
+    },
+    BadCoinbaseReward {
+        expected: u64,
+        got: u64,
+    },
+    BadCoinbaseKey,
+    TooManyEntries(usize),
+    BodyTooLarge(usize),
+    BadSignature {
+        index: usize,
+    },
+    ExpiredEntry {
+        index: usize,
+    },
+    DuplicateEntry {
+        index: usize,
+    },
+    BadMerkleRoot,
+    BadTransaction {
+        index: usize,
+        reason: TransactionError,
+    },
+    BadUpinfoFee {
+        index: usize,
+        reason: TransactionError,
+    },
+    Storage(String), // 块本身没问题，但是写盘失败了
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockValidationError::AlreadyKnown => write!(f, "block is already known"),
+            BlockValidationError::GenesisMismatch => {
+                write!(f, "genesis block does not match this network")
+            }
+            BlockValidationError::UnknownParent => write!(f, "parent block is unknown"),
+            BlockValidationError::BadHeight { expected, got } => {
+                write!(f, "invalid height {}, expected {}", got, expected)
+            }
+            BlockValidationError::UnexpectedTarget { expected, got } => {
+                write!(
+                    f,
+                    "unexpected target {:08x}, expected {:08x}",
+                    got, expected
+                )
+            }
+            BlockValidationError::BadProofOfWork => write!(f, "hash does not meet the target"),
+            BlockValidationError::TimestampTooOld {
+                timestamp,
+                median_time_past,
+            } => write!(
+                f,
+                "timestamp {} is not after median time past {}",
+                timestamp, median_time_past
+            ),
+            BlockValidationError::TimestampTooFarInFuture { timestamp, now } => write!(
+                f,
+                "timestamp {} is too far in the future (local time {})",
+                timestamp, now
+            ),
+            BlockValidationError::BadCoinbaseReward { expected, got } => {
+                write!(f, "invalid coinbase reward {}, expected {}", got, expected)
+            }
+            BlockValidationError::BadCoinbaseKey => {
+                write!(f, "coinbase public key is not a valid ed25519 key")
+            }
+            BlockValidationError::TooManyEntries(n) => write!(f, "too many entries: {}", n),
+            BlockValidationError::BodyTooLarge(n) => write!(f, "body too large: {} bytes", n),
+            BlockValidationError::BadSignature { index } => {
+                write!(f, "upinfo {} has an invalid signature", index)
+            }
+            BlockValidationError::ExpiredEntry { index } => {
+                write!(f, "upinfo {} is expired or not yet includable", index)
+            }
+            BlockValidationError::DuplicateEntry { index } => {
+                write!(f, "upinfo {} is already in the chain", index)
+            }
+            BlockValidationError::BadMerkleRoot => write!(f, "merkle root does not match body"),
+            BlockValidationError::BadTransaction { index, reason } => {
+                write!(f, "transaction {} is invalid: {}", index, reason)
+            }
+            BlockValidationError::BadUpinfoFee { index, reason } => {
+                write!(f, "upinfo {} can not pay its fee: {}", index, reason)
+            }
+            BlockValidationError::Storage(e) => write!(f, "can not persist block: {}", e),
+        }
     }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Block {
     pub height: usize,
-    pub previous_hash: Vec<u8>,
+    pub previous_hash: Hash,
     pub timestamp: Timestamp,
-    pub merkle_root: [u8; 32],
+    pub merkle_root: Hash,
+    pub bits: u32, // 压缩格式的难度目标，见difficulty.rs
     pub nonce: u128,
-    pub upinfo: Vec<String>,
+    pub coinbase: Coinbase,
+    pub upinfo: Vec<SignedEntry>,
+    pub transactions: Vec<Transaction>,
+    #[serde(default)] // 加这个字段之前存下的块中没有它
+    pub utxo_transactions: Vec<UtxoTransaction>,
+}
+
+impl Block {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            height: self.height as u64,
+            previous_hash: self.previous_hash,
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root,
+            bits: self.bits,
+            nonce: self.nonce,
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.header().hash()
+    }
+
+    // 用块体算出默克尔根。空块也至少有coinbase这一个叶子，所以一定算得出来
+    pub fn compute_merkle_root(&self) -> Hash {
+        MerkleTree::<Sha256>::from_leaves(&merkle_leaves(self))
+            .root()
+            .unwrap()
+    }
+
+    pub fn body_size(&self) -> usize {
+        self.upinfo.iter().map(|n| n.encoded_len()).sum::<usize>()
+            + self
+                .transactions
+                .iter()
+                .map(|n| n.encoded_len())
+                .sum::<usize>()
+            + self
+                .utxo_transactions
+                .iter()
+                .map(|n| n.encoded_len())
+                .sum::<usize>()
+    }
+}
+
+// 块的出块奖励记录，作为0号默克尔叶子，记下是哪个矿工挖出了这个块
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Coinbase {
+    pub public_key: Vec<u8>, // 矿工的ed25519公钥，创世块为空
+    pub reward: u64,
+}
+
+impl Coinbase {
+    // 叶子的原像：4字节大端序长度 + public_key，后面跟8字节大端序的reward
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12 + self.public_key.len());
+        buf.extend_from_slice(&(self.public_key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.public_key);
+        buf.extend_from_slice(&self.reward.to_be_bytes());
+        buf
+    }
+
+    pub fn leaf_hash(&self) -> Hash {
+        Sha256::hash(&self.encode())
+    }
+}
+
+// 块中存放的一条上链信息。签名和公钥也跟着上链，之后同步链的节点可以自己验证每条upinfo是谁提交的
+// expires_at是签名时定下的过期高度，一条upinfo只能在expires_at及之前的UPINFO_MAX_LIFETIME个高度内上链，
+// 再加上链上不能有重复，同一个签名就没法被别人反复广播、反复上链了。
+// fee和upinfo一起签名，上链时从public_key的账户中扣给矿工；UTXO模型的链没有账户，fee只能是0
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SignedEntry {
+    pub upinfo: String,
+    pub expires_at: usize,
+    #[serde(default)]
+    pub fee: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedEntry {
+    // 签名的原文：4字节大端序长度 + upinfo，后面跟8字节大端序的expires_at和fee
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(20 + self.upinfo.len());
+        buf.extend_from_slice(&(self.upinfo.len() as u32).to_be_bytes());
+        buf.extend_from_slice(self.upinfo.as_bytes());
+        buf.extend_from_slice(&(self.expires_at as u64).to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_be_bytes());
+        buf
+    }
+
+    // 默克尔树叶子的原像：upinfo、public_key、signature依次拼接，每个字段前面带4字节大端序长度，最后是8字节大端序的expires_at和fee
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        for field in [
+            self.upinfo.as_bytes(),
+            &self.public_key[..],
+            &self.signature[..],
+        ] {
+            buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            buf.extend_from_slice(field);
+        }
+        buf.extend_from_slice(&(self.expires_at as u64).to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_be_bytes());
+        buf
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        28 + self.upinfo.len() + self.public_key.len() + self.signature.len()
+    }
+
+    pub fn leaf_hash(&self) -> Hash {
+        Sha256::hash(&self.encode())
+    }
+
+    // 判重用的标识，只包含公钥和签名的原文，不包含签名本身
+    pub fn replay_id(&self) -> Hash {
+        let mut buf = (self.public_key.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.public_key);
+        buf.extend_from_slice(&self.signing_bytes());
+        Sha256::hash(&buf)
+    }
+
+    // 这条upinfo能不能打包进高度为height的块
+    pub fn is_live_at(&self, height: usize) -> bool {
+        height <= self.expires_at && self.expires_at < height + UPINFO_MAX_LIFETIME
+    }
+
+    // 钱包提交upinfo时用这个生成带签名的SignedEntry
+    pub fn new_signed(
+        upinfo: String,
+        expires_at: usize,
+        fee: u64,
+        chain_id: &ChainId,
+        keypair: &ed25519_dalek::Keypair,
+    ) -> Self {
+        let mut entry = SignedEntry {
+            upinfo,
+            expires_at,
+            fee,
+            public_key: keypair.public.to_bytes().to_vec(),
+            signature: vec![],
+        };
+        entry.signature = cryptography::sign(
+            chain_id,
+            MessageTag::Upinfo,
+            &entry.signing_bytes(),
+            keypair,
+        );
+        entry
+    }
+
+    pub fn verify(&self, chain_id: &ChainId) -> bool {
+        cryptography::verify(
+            &self.public_key,
+            chain_id,
+            MessageTag::Upinfo,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+}
+
+// 一个块的工作量，即找到满足难度要求的哈希平均需要尝试的次数
+pub fn block_work(block: &Block) -> u128 {
+    difficulty::work_from_bits(block.bits)
+}
+
+// 默克尔树的叶子：coinbase在最前面，然后依次是每条SignedEntry编码后的sha256、每笔账户交易的哈希、每笔UTXO交易的叶子哈希
+fn merkle_leaves(block: &Block) -> Vec<Hash> {
+    std::iter::once(block.coinbase.leaf_hash())
+        .chain(block.upinfo.iter().map(|n| n.leaf_hash()))
+        .chain(block.transactions.iter().map(|n| n.hash()))
+        .chain(block.utxo_transactions.iter().map(|n| n.leaf_hash()))
+        .collect()
+}
+
+// 某条upinfo在块中的默克尔包含证明。客户端只要有块头，不用下载块体就能确认这条upinfo确实上链了
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub block_hash: Hash,
+    pub index: usize,        // 叶子下标，0号叶子是coinbase，第i条upinfo是i+1号
+    pub total_leaves: usize, // 块中默克尔叶子的总数
+    pub entry: SignedEntry,
+    pub proof: Vec<u8>, // rs_merkle的MerkleProof::to_bytes，即从叶子到根路径上的兄弟节点哈希
+}
+
+impl InclusionProof {
+    // 不依赖Chain，只用块头验证。证明的是entry在这个块里；
+    // 默克尔根并不约束index和total_leaves，个别情况下换一组index/total_leaves也能验过，不要拿它们当作可信的位置
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        if header.hash() != self.block_hash || self.index >= self.total_leaves {
+            return false;
+        }
+        let proof = match MerkleProof::<Sha256>::from_bytes(&self.proof) {
+            Ok(proof) => proof,
+            Err(_) => return false,
+        };
+        // 兄弟节点哈希的个数不对时rs_merkle会panic，所以先自己数一下这个下标需要几个
+        let (mut index, mut width, mut needed) = (self.index, self.total_leaves, 0);
+        while width > 1 {
+            if index ^ 1 < width {
+                needed += 1;
+            }
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+        needed == proof.proof_hashes().len()
+            && proof.verify(
+                header.merkle_root,
+                &[self.index],
+                &[self.entry.leaf_hash()],
+                self.total_leaves,
+            )
+    }
+}
+
+// 参与哈希计算的块头。挖矿和验证都只通过BlockHeader::hash计算块哈希
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub height: u64,
+    pub previous_hash: Hash,
+    pub timestamp: i64,
+    pub merkle_root: Hash,
+    pub bits: u32,
+    pub nonce: u128,
+}
+
+impl BlockHeader {
+    pub const ENCODED_LEN: usize = 8 + 32 + 8 + 32 + 4 + 16;
+
+    // 定长编码，整数一律大端序：
+    //      height(8) | previous_hash(32) | timestamp(8) | merkle_root(32) | bits(4) | nonce(16)
+    pub fn encode(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut buf = [0u8; Self::ENCODED_LEN];
+        buf[0..8].copy_from_slice(&self.height.to_be_bytes());
+        buf[8..40].copy_from_slice(&self.previous_hash);
+        buf[40..48].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[48..80].copy_from_slice(&self.merkle_root);
+        buf[80..84].copy_from_slice(&self.bits.to_be_bytes());
+        buf[84..100].copy_from_slice(&self.nonce.to_be_bytes());
+        buf
+    }
+
+    pub fn hash(&self) -> Hash {
+        sha2_sha256::digest(self.encode()).into()
+    }
+}
+
+// 测试用的链和块。block.rs之外的测试也会用到
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::ledger::AccountLedger;
+    use crate::utxo::UtxoLedger;
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    // 难度上限很低的测试网，平均两次哈希就能挖出一个块
+    pub fn regtest_spec() -> GenesisSpec {
+        GenesisSpec {
+            pow_limit_bits: 0x207f_ffff,
+            ..GenesisSpec::runchainnet()
+        }
+    }
+
+    // 和regtest_spec一样，只是账本是UTXO模型，默克尔根也随之不同
+    pub fn utxo_regtest_spec() -> GenesisSpec {
+        GenesisSpec {
+            merkle_root: "e3981f45c361e31a72ee142d6380867bec3e5989ba91e40ab7d380b026d2f47c"
+                .to_string(),
+            ledger: LedgerKind::Utxo,
+            ..regtest_spec()
+        }
+    }
+
+    // 可以随时拨动的时钟
+    #[derive(Clone)]
+    pub struct TestClock(pub Arc<AtomicI64>);
+
+    impl TestClock {
+        pub fn set(&self, now_millis: i64) {
+            self.0.store(now_millis, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now_millis(&self) -> i64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    // 内存中的测试链，本地时间停在创世块之后一天
+    pub fn regtest_chain(spec: &GenesisSpec) -> (Chain, TestClock) {
+        let clock = TestClock(Arc::new(AtomicI64::new(spec.timestamp + 86_400_000)));
+        let chain = Chain::new(
+            spec.to_block().unwrap(),
+            spec.issuance,
+            spec.ledger,
+            Box::new(clock.clone()),
+        );
+        (chain, clock)
+    }
+
+    // 由seed决定的固定密钥，每次运行都一样
+    pub fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    // 接在parent后面、时间戳为timestamp的空块，已经挖好
+    pub fn child_of(chain: &Chain, parent: &Block, timestamp: i64, miner: &Keypair) -> Block {
+        let height = parent.height + 1;
+        let mut block = Block {
+            height,
+            previous_hash: parent.hash(),
+            timestamp,
+            merkle_root: [0; 32],
+            bits: chain.next_bits(parent),
+            nonce: 0,
+            coinbase: Coinbase {
+                public_key: miner.public.to_bytes().to_vec(),
+                reward: chain.block_reward(height),
+            },
+            upinfo: vec![],
+            transactions: vec![],
+            utxo_transactions: vec![],
+        };
+        block.merkle_root = block.compute_merkle_root();
+        mine(block)
+    }
+
+    fn mine(mut block: Block) -> Block {
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        block
+    }
+
+    // 在主链末尾接上n个空块，每个块比前一个晚TARGET_BLOCK_TIME_MS
+    pub fn extend(chain: &mut Chain, n: usize, miner: &Keypair) {
+        for _ in 0..n {
+            let parent = chain.last_block().clone();
+            let block = child_of(
+                chain,
+                &parent,
+                parent.timestamp + TARGET_BLOCK_TIME_MS,
+                miner,
+            );
+            chain.try_add_a_block(block).unwrap();
+        }
+    }
+
+    // 按账本模型区分的测试链：用哪份规格，怎么从Ledger里取出具体的账本
+    pub trait TestLedger: Clone {
+        fn spec() -> GenesisSpec;
+        fn of(ledger: &Ledger) -> &Self;
+    }
+
+    impl TestLedger for AccountLedger {
+        fn spec() -> GenesisSpec {
+            regtest_spec()
+        }
+
+        fn of(ledger: &Ledger) -> &Self {
+            match ledger {
+                Ledger::Account(ledger) => ledger,
+                Ledger::Utxo(_) => unreachable!(),
+            }
+        }
+    }
+
+    impl TestLedger for UtxoLedger {
+        fn spec() -> GenesisSpec {
+            utxo_regtest_spec()
+        }
+
+        fn of(ledger: &Ledger) -> &Self {
+            match ledger {
+                Ledger::Utxo(ledger) => ledger,
+                Ledger::Account(_) => unreachable!(),
+            }
+        }
+    }
+
+    // keypair(1)挖了一个块的链，账本模型由L决定，返回链和它末尾的账本
+    pub fn funded<L: TestLedger>() -> (Chain, L) {
+        let (mut chain, _) = regtest_chain(&L::spec());
+        extend(&mut chain, 1, &keypair(1));
+        let ledger = L::of(chain.ledger()).clone();
+        (chain, ledger)
+    }
+
+    // 每个测试用自己的临时目录
+    pub fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("runchain-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_header() -> BlockHeader {
+        BlockHeader {
+            height: 1,
+            previous_hash: [0x11; 32],
+            timestamp: 1653004800000,
+            merkle_root: [0x22; 32],
+            bits: 0x1dff_ffff,
+            nonce: 42,
+        }
+    }
+
+    // 测试向量是用Python的struct和hashlib独立算出来的，改动块头编码时它们必须跟着变
+    #[test]
+    fn header_encoding_test_vector() {
+        assert_eq!(
+            hex::encode(sample_header().encode()),
+            "0000000000000001\
+             1111111111111111111111111111111111111111111111111111111111111111\
+             00000180dec39000\
+             2222222222222222222222222222222222222222222222222222222222222222\
+             1dffffff\
+             0000000000000000000000000000002a"
+        );
+    }
+
+    #[test]
+    fn header_hash_test_vectors() {
+        assert_eq!(
+            hex::encode(sample_header().hash()),
+            "5996beda34149c941f077209f95dc3878e1b79a5238e265e7e79899ca84be2d2"
+        );
+        let zero = BlockHeader {
+            height: 0,
+            previous_hash: [0; 32],
+            timestamp: 0,
+            merkle_root: [0; 32],
+            bits: 0,
+            nonce: 0,
+        };
+        assert_eq!(
+            hex::encode(zero.hash()),
+            "cd00e292c5970d3c5e2f0ffa5171e555bc46bfc4faddfb4a418b6840b86e79a3"
+        );
+        // 负的时间戳按补码编码，nonce用满16字节
+        let extreme = BlockHeader {
+            height: 7,
+            timestamp: -1,
+            bits: 0x207f_ffff,
+            nonce: u128::MAX,
+            ..sample_header()
+        };
+        assert_eq!(
+            hex::encode(extreme.hash()),
+            "bebed1243af93436385211f1c5494bb723e837211a16d3c0bcb66df99ae6cb57"
+        );
+    }
+
+    #[test]
+    fn every_header_field_changes_the_hash() {
+        let header = sample_header();
+        let variants = [
+            BlockHeader {
+                height: 2,
+                ..header
+            },
+            BlockHeader {
+                previous_hash: [0x12; 32],
+                ..header
+            },
+            BlockHeader {
+                timestamp: header.timestamp + 1,
+                ..header
+            },
+            BlockHeader {
+                merkle_root: [0x23; 32],
+                ..header
+            },
+            BlockHeader {
+                bits: 0x1d00_ffff,
+                ..header
+            },
+            BlockHeader {
+                nonce: 43,
+                ..header
+            },
+        ];
+        for variant in variants {
+            assert_ne!(variant.hash(), header.hash());
+        }
+    }
+
+    #[test]
+    fn block_hash_is_its_header_hash() {
+        let block = GenesisSpec::runchainnet().to_block().unwrap();
+        assert_eq!(block.hash(), block.header().hash());
+        let mined = Block {
+            nonce: 1,
+            ..block.clone()
+        };
+        assert_ne!(mined.hash(), block.hash());
+    }
+}
+
+#[cfg(test)]
+mod timestamp_tests {
+    use super::testing::*;
+    use super::*;
+
+    #[test]
+    fn timestamp_must_be_after_median_time_past() {
+        let spec = regtest_spec();
+        let (mut chain, _) = regtest_chain(&spec);
+        let miner = keypair(1);
+        extend(&mut chain, 4, &miner);
+
+        let parent = chain.last_block().clone();
+        let median = chain.median_time_past(&parent);
+        // 5个块的时间戳依次相差TARGET_BLOCK_TIME_MS，中位数是第3个
+        assert_eq!(median, spec.timestamp + 2 * TARGET_BLOCK_TIME_MS);
+
+        let at_median = child_of(&chain, &parent, median, &miner);
+        assert_eq!(
+            chain.try_add_a_block(at_median).err(),
+            Some(BlockValidationError::TimestampTooOld {
+                timestamp: median,
+                median_time_past: median,
+            })
+        );
+        // 可以比父块早，只要比中位数晚
+        let before_parent = child_of(&chain, &parent, median + 1, &miner);
+        assert!(before_parent.timestamp < parent.timestamp);
+        assert!(chain.try_add_a_block(before_parent).is_ok());
+    }
+
+    #[test]
+    fn median_time_past_only_looks_at_the_last_blocks() {
+        let spec = regtest_spec();
+        let (mut chain, _) = regtest_chain(&spec);
+        extend(&mut chain, 20, &keypair(1));
+        let parent = chain.last_block();
+        // 高度10到20这11个块的中位数是高度15
+        assert_eq!(
+            chain.median_time_past(parent),
+            chain.get_by_height(15).unwrap().timestamp
+        );
+    }
+
+    #[test]
+    fn timestamp_must_not_run_ahead_of_the_local_clock() {
+        let spec = regtest_spec();
+        let (mut chain, clock) = regtest_chain(&spec);
+        let miner = keypair(1);
+        let now = spec.timestamp + 60_000;
+        clock.set(now);
+
+        let parent = chain.last_block().clone();
+        let at_limit = child_of(&chain, &parent, now + MAX_FUTURE_DRIFT_MS, &miner);
+        assert!(chain.try_add_a_block(at_limit).is_ok());
+
+        let parent = chain.last_block().clone();
+        let too_far = child_of(&chain, &parent, now + MAX_FUTURE_DRIFT_MS + 1, &miner);
+        assert_eq!(
+            chain.try_add_a_block(too_far.clone()).err(),
+            Some(BlockValidationError::TimestampTooFarInFuture {
+                timestamp: now + MAX_FUTURE_DRIFT_MS + 1,
+                now,
+            })
+        );
+        // 本地时间追上来之后同一个块就能接受了
+        clock.set(now + 1);
+        assert!(chain.try_add_a_block(too_far).is_ok());
+    }
+
+    #[test]
+    fn replayed_blocks_ignore_the_local_clock() {
+        let spec = regtest_spec();
+        let (mut chain, clock) = regtest_chain(&spec);
+        extend(&mut chain, 3, &keypair(1));
+
+        // 本地时钟被往回拨到创世块之前，已经接受过的块重放时仍然有效
+        clock.set(spec.timestamp - 86_400_000);
+        let (mut replay, replay_clock) = regtest_chain(&spec);
+        replay_clock.set(spec.timestamp - 86_400_000);
+        for block in chain.main_chain().iter().skip(1) {
+            assert!(replay.replay_block(block.clone()).is_ok());
+        }
+        assert_eq!(replay.tip_hash(), chain.tip_hash());
+        let (mut verified, _) = regtest_chain(&spec);
+        assert_eq!(verified.verify_all(chain.main_chain().to_vec()), Ok(3));
+    }
+
+    #[test]
+    fn imported_blocks_must_not_run_ahead_of_the_local_clock() {
+        let spec = regtest_spec();
+        let (mut chain, _) = regtest_chain(&spec);
+        // 导出文件中的块比本地时间晚一天
+        let future = chrono::Utc::now().timestamp_millis() + 86_400_000;
+        let parent = chain.last_block().clone();
+        let block = child_of(&chain, &parent, future, &keypair(1));
+        chain.replay_block(block).unwrap();
+
+        let (mut imported, _) = regtest_chain(&spec);
+        assert!(matches!(
+            imported.verify_new(chain.main_chain().to_vec()),
+            Err((1, BlockValidationError::TimestampTooFarInFuture { .. }))
+        ));
+        let (mut verified, _) = regtest_chain(&spec);
+        assert_eq!(verified.verify_all(chain.main_chain().to_vec()), Ok(1));
+    }
+
+    #[test]
+    fn reload_keeps_blocks_from_the_local_future() {
+        let spec = regtest_spec();
+        let dir = temp_dir("future-blocks");
+        let (mut chain, _) = regtest_chain(&spec);
+        // 这些块的时间戳比真实的本地时间晚一天，好比写盘之后系统时钟被往回拨了
+        let future = chrono::Utc::now().timestamp_millis() + 86_400_000;
+        let miner = keypair(1);
+        for i in 0..3 {
+            let parent = chain.last_block().clone();
+            let block = child_of(&chain, &parent, future + i * TARGET_BLOCK_TIME_MS, &miner);
+            chain.replay_block(block).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let (mut store, _) = BlockStore::open(dir.join("blocks.dat")).unwrap();
+        for block in chain.main_chain() {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        let reloaded = Chain::open(&dir, &spec).unwrap();
+        assert_eq!(reloaded.tip_hash(), chain.tip_hash());
+        let (_, stored) = BlockStore::open(dir.join("blocks.dat")).unwrap();
+        assert_eq!(stored.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_a_bad_stored_block_without_truncating() {
+        let spec = regtest_spec();
+        let dir = temp_dir("bad-stored-block");
+        let (mut chain, _) = regtest_chain(&spec);
+        extend(&mut chain, 3, &keypair(1));
+        fs::create_dir_all(&dir).unwrap();
+        let (mut store, _) = BlockStore::open(dir.join("blocks.dat")).unwrap();
+        for block in chain.main_chain() {
+            let mut block = block.clone();
+            // 改坏高度为2的块的奖励
+            if block.height == 2 {
+                block.coinbase.reward += 1;
+            }
+            store.append(&block).unwrap();
+        }
+        drop(store);
+
+        let stored = BlockStore::read_all(dir.join("blocks.dat")).unwrap();
+        let (mut verified, _) = regtest_chain(&spec);
+        assert_eq!(verified.verify_all(stored).map_err(|(n, _)| n), Err(2));
+        Chain::open_read_only(&dir, &spec).unwrap();
+        assert_eq!(
+            BlockStore::read_all(dir.join("blocks.dat")).unwrap().len(),
+            4
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_refuses_a_bad_stored_block_without_truncating() {
+        let spec = regtest_spec();
+        let dir = temp_dir("open-bad-stored-block");
+        let (mut chain, _) = regtest_chain(&spec);
+        extend(&mut chain, 3, &keypair(1));
+        fs::create_dir_all(&dir).unwrap();
+        let (mut store, _) = BlockStore::open(dir.join("blocks.dat")).unwrap();
+        for block in chain.main_chain() {
+            let mut block = block.clone();
+            // 记录本身是完好的，只是高度为2的块通不过共识检查
+            if block.height == 2 {
+                block.coinbase.reward += 1;
+            }
+            store.append(&block).unwrap();
+        }
+        drop(store);
+        let size = fs::metadata(dir.join("blocks.dat")).unwrap().len();
+
+        let err = Chain::open(&dir, &spec).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(dir.join("blocks.dat")).unwrap().len(), size);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod reorg_tests {
+    use super::testing::*;
+    use super::*;
+    use crate::transaction::Transaction;
+    use ed25519_dalek::Keypair;
+
+    fn next(chain: &Chain, parent: &Block, miner: &Keypair) -> Block {
+        child_of(
+            chain,
+            parent,
+            parent.timestamp + TARGET_BLOCK_TIME_MS,
+            miner,
+        )
+    }
+
+    // 和next一样，只是块中带着transactions
+    fn next_with(
+        chain: &Chain,
+        parent: &Block,
+        miner: &Keypair,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        let mut block = next(chain, parent, miner);
+        block.transactions = transactions;
+        block.merkle_root = block.compute_merkle_root();
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        block
+    }
+
+    fn key(keypair: &Keypair) -> Vec<u8> {
+        keypair.public.to_bytes().to_vec()
+    }
+
+    #[test]
+    fn fork_takes_over_once_it_has_more_work() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let (alice, bob) = (keypair(1), keypair(2));
+        let genesis = chain.last_block().clone();
+        let genesis_work = chain.tip_work();
+
+        let first = next(&chain, &genesis, &alice);
+        assert!(matches!(
+            chain.try_add_a_block(first.clone()),
+            Ok(AddBlockOutcome::Extended)
+        ));
+        extend(&mut chain, 1, &alice);
+        let old_main: Vec<Hash> = chain.main_chain()[1..].iter().map(Block::hash).collect();
+        let old_tip = chain.tip_hash();
+
+        // 从创世块分出去的分叉，工作量没有超过主链之前只放在分叉上
+        let mut parent = genesis;
+        for _ in 0..2 {
+            let block = next(&chain, &parent, &bob);
+            assert!(matches!(
+                chain.try_add_a_block(block.clone()),
+                Ok(AddBlockOutcome::SideBranch)
+            ));
+            parent = block;
+        }
+        assert_eq!(chain.tip_hash(), old_tip);
+        assert_eq!(chain.balance(&key(&bob)), 0);
+
+        // 再多一个块就比主链的工作量大，换成主链
+        let block = next(&chain, &parent, &bob);
+        match chain.try_add_a_block(block.clone()).unwrap() {
+            AddBlockOutcome::Reorganized {
+                disconnected,
+                connected,
+            } => {
+                let disconnected: Vec<Hash> = disconnected.iter().map(Block::hash).collect();
+                assert_eq!(disconnected, old_main);
+                assert_eq!(connected.len(), 3);
+                assert_eq!(connected[2].hash(), block.hash());
+            }
+            other => panic!("expected a reorganization, got {:?}", other),
+        }
+        assert_eq!(chain.tip_hash(), block.hash());
+        assert_eq!(chain.last_block().height, 3);
+        assert_eq!(chain.tip_work(), genesis_work + 3 * block_work(&block));
+        assert_eq!(chain.balance(&key(&alice)), 0);
+        let rewards: u64 = (1..=3).map(|n| chain.block_reward(n)).sum();
+        assert_eq!(chain.balance(&key(&bob)), rewards);
+
+        // 撤下来的块还在块树里，同一个块再来就是已知的
+        assert_eq!(
+            chain.try_add_a_block(first).unwrap_err(),
+            BlockValidationError::AlreadyKnown
+        );
+    }
+
+    #[test]
+    fn reorg_rolls_the_ledger_back_and_forward() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let (alice, bob, carol) = (keypair(1), keypair(2), keypair(3));
+        extend(&mut chain, 1, &alice);
+        let fork_point = chain.last_block().clone();
+        let reward = |n| chain.block_reward(n);
+        let (r1, r2, r3, r4) = (reward(1), reward(2), reward(3), reward(4));
+
+        // 主链：alice在高度2给carol转了10
+        let pay = Transaction::new_signed(key(&carol), 10, 0, 0, &chain.chain_id(), &alice);
+        let a2 = next_with(&chain, &fork_point, &alice, vec![pay]);
+        chain.try_add_a_block(a2.clone()).unwrap();
+        assert_eq!(chain.balance(&key(&carol)), 10);
+
+        // bob的分叉超过主链，转账被撤回
+        let b2 = next(&chain, &fork_point, &bob);
+        chain.try_add_a_block(b2.clone()).unwrap();
+        let b3 = next(&chain, &b2, &bob);
+        assert!(matches!(
+            chain.try_add_a_block(b3.clone()),
+            Ok(AddBlockOutcome::Reorganized { .. })
+        ));
+        assert_eq!(chain.balance(&key(&carol)), 0);
+        assert_eq!(chain.balance(&key(&alice)), r1);
+        assert_eq!(chain.balance(&key(&bob)), r2 + r3);
+
+        // alice的分叉再追上来，转账重新生效
+        let a3 = next(&chain, &a2, &alice);
+        assert!(matches!(
+            chain.try_add_a_block(a3.clone()),
+            Ok(AddBlockOutcome::SideBranch)
+        ));
+        let a4 = next(&chain, &a3, &alice);
+        assert!(matches!(
+            chain.try_add_a_block(a4.clone()),
+            Ok(AddBlockOutcome::Reorganized { .. })
+        ));
+        assert_eq!(chain.tip_hash(), a4.hash());
+        assert_eq!(chain.balance(&key(&carol)), 10);
+        assert_eq!(chain.balance(&key(&alice)), r1 + r2 + r3 + r4 - 10);
+        assert_eq!(chain.balance(&key(&bob)), 0);
+    }
+
+    #[test]
+    fn side_blocks_are_checked_against_their_own_branch() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let (alice, bob) = (keypair(1), keypair(2));
+        let genesis = chain.last_block().clone();
+        extend(&mut chain, 2, &alice);
+
+        // alice的钱只在主链上，从创世块分出去的分叉上她还没有钱
+        let pay = Transaction::new_signed(key(&bob), 1, 0, 0, &chain.chain_id(), &alice);
+        let fork = next_with(&chain, &genesis, &bob, vec![pay]);
+        assert!(matches!(
+            chain.try_add_a_block(fork),
+            Err(BlockValidationError::BadTransaction {
+                index: 0,
+                reason: TransactionError::InsufficientBalance { .. }
+            })
+        ));
+        assert_eq!(chain.last_block().height, 2);
+    }
+}
+
+#[cfg(test)]
+mod proof_tests {
+    use super::testing::*;
+    use super::*;
+
+    // 主链上接一个带n条upinfo的块，加上coinbase一共n+1个默克尔叶子
+    fn chain_with_entries(n: usize) -> (Chain, Block) {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let alice = keypair(1);
+        let parent = chain.last_block().clone();
+        let mut block = child_of(
+            &chain,
+            &parent,
+            parent.timestamp + TARGET_BLOCK_TIME_MS,
+            &alice,
+        );
+        block.upinfo = (0..n)
+            .map(|i| {
+                SignedEntry::new_signed(format!("entry {}", i), 10, 0, &chain.chain_id(), &alice)
+            })
+            .collect();
+        block.merkle_root = block.compute_merkle_root();
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        chain.try_add_a_block(block.clone()).unwrap();
+        (chain, block)
+    }
+
+    #[test]
+    fn every_entry_has_a_valid_proof() {
+        for n in 0..=8 {
+            let (chain, block) = chain_with_entries(n);
+            let hash = block.hash();
+            for i in 0..n {
+                let proof = chain.inclusion_proof(&hash, i).unwrap();
+                assert_eq!(proof.index, i + 1);
+                assert_eq!(proof.total_leaves, n + 1);
+                assert!(proof.verify(&block.header()), "{} entries, index {}", n, i);
+                let text = format!("entry {}", i);
+                assert_eq!(chain.inclusion_proof_of(&hash, &text), Some(proof));
+            }
+            assert_eq!(chain.inclusion_proof(&hash, n), None);
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_do_not_verify() {
+        let (chain, block) = chain_with_entries(5);
+        let header = block.header();
+        let proof = chain.inclusion_proof(&block.hash(), 2).unwrap();
+
+        let mut leaf = proof.clone();
+        leaf.entry.upinfo.push('!');
+        assert!(!leaf.verify(&header));
+
+        let mut sibling = proof.clone();
+        sibling.proof[0] ^= 1;
+        assert!(!sibling.verify(&header));
+
+        // 换了默克尔根的块头，证明里的块哈希也跟着换，只有根对不上
+        let mut other_header = header;
+        other_header.merkle_root[0] ^= 1;
+        let mut root = proof.clone();
+        root.block_hash = other_header.hash();
+        assert!(!root.verify(&other_header));
+    }
+
+    #[test]
+    fn wrong_number_of_siblings_is_rejected_without_panicking() {
+        for n in 1..=8 {
+            let (chain, block) = chain_with_entries(n);
+            let header = block.header();
+            for i in 0..n {
+                let proof = chain.inclusion_proof(&block.hash(), i).unwrap();
+
+                let mut truncated = proof.clone();
+                truncated.proof.truncate(proof.proof.len() - 32);
+                assert!(!truncated.verify(&header));
+
+                let mut extended = proof.clone();
+                extended.proof.extend_from_slice(&[0x5a; 32]);
+                assert!(!extended.verify(&header));
+
+                let mut ragged = proof.clone();
+                ragged.proof.push(0);
+                assert!(!ragged.verify(&header));
+
+                // 兄弟节点没变，但叶子总数改得需要更多或更少的兄弟节点
+                for total_leaves in [i + 2, 64, 1000] {
+                    let mut resized = proof.clone();
+                    resized.total_leaves = total_leaves;
+                    let _ = resized.verify(&header);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use super::testing::*;
+    use super::*;
+
+    // 接在parent后面、带着entries的块，已经挖好
+    fn with_upinfo(chain: &Chain, parent: &Block, entries: Vec<SignedEntry>) -> Block {
+        let timestamp = parent.timestamp + TARGET_BLOCK_TIME_MS;
+        let mut block = child_of(chain, parent, timestamp, &keypair(9));
+        block.upinfo = entries;
+        block.merkle_root = block.compute_merkle_root();
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        block
+    }
+
+    fn entry(chain: &Chain, text: &str, expires_at: usize) -> SignedEntry {
+        SignedEntry::new_signed(
+            text.to_string(),
+            expires_at,
+            0,
+            &chain.chain_id(),
+            &keypair(1),
+        )
+    }
+
+    #[test]
+    fn expired_upinfo_is_rejected() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        extend(&mut chain, 3, &keypair(2));
+        let parent = chain.last_block().clone();
+        // 下一个块的高度是4，过期高度为3的upinfo已经不能上链了
+        let expired = entry(&chain, "expired", 3);
+        let block = with_upinfo(&chain, &parent, vec![entry(&chain, "ok", 4), expired]);
+        assert_eq!(
+            chain.try_add_a_block(block).err(),
+            Some(BlockValidationError::ExpiredEntry { index: 1 })
+        );
+        let last_chance = with_upinfo(&chain, &parent, vec![entry(&chain, "ok", 4)]);
+        assert!(chain.try_add_a_block(last_chance).is_ok());
+    }
+
+    #[test]
+    fn expiry_beyond_the_window_is_rejected() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let parent = chain.last_block().clone();
+        let too_far = entry(&chain, "too far", 1 + UPINFO_MAX_LIFETIME);
+        let block = with_upinfo(&chain, &parent, vec![too_far]);
+        assert_eq!(
+            chain.try_add_a_block(block).err(),
+            Some(BlockValidationError::ExpiredEntry { index: 0 })
+        );
+        let farthest = entry(&chain, "farthest", UPINFO_MAX_LIFETIME);
+        let block = with_upinfo(&chain, &parent, vec![farthest]);
+        assert!(chain.try_add_a_block(block).is_ok());
+    }
+
+    #[test]
+    fn upinfo_already_in_a_recent_block_is_rejected() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let genesis = chain.last_block().clone();
+        let once = entry(&chain, "once", UPINFO_MAX_LIFETIME);
+        let first = with_upinfo(&chain, &genesis, vec![once.clone()]);
+        chain.try_add_a_block(first.clone()).unwrap();
+        extend(&mut chain, 5, &keypair(2));
+
+        let parent = chain.last_block().clone();
+        let replayed = with_upinfo(&chain, &parent, vec![once.clone()]);
+        assert_eq!(
+            chain.try_add_a_block(replayed).err(),
+            Some(BlockValidationError::DuplicateEntry { index: 0 })
+        );
+        // 同一个块里出现两次也不行
+        let other = entry(&chain, "twice", UPINFO_MAX_LIFETIME);
+        let twice = with_upinfo(&chain, &parent, vec![other.clone(), other]);
+        assert_eq!(
+            chain.try_add_a_block(twice).err(),
+            Some(BlockValidationError::DuplicateEntry { index: 1 })
+        );
+        // 没有包含它的分叉上可以再上链
+        let fork_only = entry(&chain, "fork", UPINFO_MAX_LIFETIME);
+        let fork = with_upinfo(&chain, &genesis, vec![fork_only, once.clone()]);
+        assert!(matches!(
+            chain.try_add_a_block(fork.clone()),
+            Ok(AddBlockOutcome::SideBranch)
+        ));
+        // 分叉上有了之后，这条分叉后面的块同样不能再包含它
+        let next = with_upinfo(&chain, &fork, vec![once]);
+        assert_eq!(
+            chain.try_add_a_block(next).err(),
+            Some(BlockValidationError::DuplicateEntry { index: 0 })
+        );
+    }
 }
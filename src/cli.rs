@@ -0,0 +1,532 @@
+// miner_node的子命令。不带子命令时miner_node作为普通节点运行
+//      miner_node verify           从创世块开始重新验证本地块文件中的每个块，只读，不改动块文件
+//      miner_node import <file>    验证导出文件中的链，全部合法才把它导入本地
+//      miner_node export [--from H1] [--to H2] [--format json|bin] <file>
+//                                  把本地主链上高度从H1到H2(包含)的块导出到文件，默认导出整条链，格式为json
+//      miner_node prove <height> <index | upinfo>
+//                                  输出主链上某个块中某条upinfo的默克尔包含证明，可以用下标或者upinfo的内容指定
+//      miner_node mined [public key]
+//                                  统计主链上由某个公钥(hex)挖出的块和拿到的奖励，默认是本节点的矿工公钥
+//      miner_node balance [public key]
+//                                  查询某个公钥(hex)在主链末尾的余额和nonce，默认是本节点的矿工公钥
+//      miner_node utxo-snapshot [--height H] <file>
+//                                  把主链上高度为H(默认是末尾)处的UTXO集合写到文件，只适用于UTXO模型的链
+//      miner_node utxo-verify <file>
+//                                  从快照文件恢复UTXO集合，检查它和本地主链上同一高度处的UTXO集合是否一致
+//      miner_node sign-upinfo [--expires H] [--fee F] <upinfo>
+//                                  用本节点的密钥给upinfo签名，输出可以直接广播的NewUPINFO(json)。
+//                                  H是过期高度，默认是主链末尾高度+UPINFO_MAX_LIFETIME/2。
+//                                  F是付给矿工的手续费，默认是0，只有账户模型的链可以带手续费
+//      miner_node sign-transfer [--fee F] [--nonce N] <to> <amount>
+//                                  用本节点的密钥签一笔转给公钥to(hex)的交易，输出可以直接广播的交易(json)。
+//                                  账户模型的链输出Transaction，F默认是0，N默认是主链末尾处本节点账户的nonce(不算交易池中还没上链的交易)；
+//                                  UTXO模型的链输出UtxoTransaction，不接受--nonce，从主链末尾处本节点的输出中按顺序挑够amount+F，
+//                                  多出来的找零给自己
+use crate::archive::{self, ArchiveFormat};
+use crate::block::{BlockValidationError, Chain, SignedEntry};
+use crate::genesis::GenesisSpec;
+use crate::keystore;
+use crate::ledger::Ledger;
+use crate::protocol::{NewUPINFO, UPINFO_MAX_LIFETIME};
+use crate::storage::BlockStore;
+use crate::transaction::Transaction;
+use crate::utxo::{TxOut, UtxoLedger, UtxoSnapshot, UtxoTransaction};
+use std::fs;
+use std::path::Path;
+
+// 返回进程的退出码
+pub fn run(args: &[String], data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    match args {
+        [cmd] if cmd == "verify" => verify(data_dir, genesis_spec),
+        [cmd, file] if cmd == "import" => import(file, data_dir, genesis_spec),
+        [cmd] if cmd == "mined" => match keystore::load_or_create(data_dir) {
+            Ok(keypair) => mined(keypair.public.as_bytes(), data_dir, genesis_spec),
+            Err(e) => {
+                println!("⛔无法读取矿工密钥:{}", e);
+                1
+            }
+        },
+        [cmd, public_key] if cmd == "mined" => match hex::decode(public_key) {
+            Ok(public_key) => mined(&public_key, data_dir, genesis_spec),
+            Err(_) => {
+                println!("⛔公钥必须是hex编码");
+                2
+            }
+        },
+        [cmd] if cmd == "balance" => match keystore::load_or_create(data_dir) {
+            Ok(keypair) => balance(keypair.public.as_bytes(), data_dir, genesis_spec),
+            Err(e) => {
+                println!("⛔无法读取矿工密钥:{}", e);
+                1
+            }
+        },
+        [cmd, public_key] if cmd == "balance" => match hex::decode(public_key) {
+            Ok(public_key) => balance(&public_key, data_dir, genesis_spec),
+            Err(_) => {
+                println!("⛔公钥必须是hex编码");
+                2
+            }
+        },
+        [cmd, file] if cmd == "utxo-snapshot" => utxo_snapshot(None, file, data_dir, genesis_spec),
+        [cmd, flag, height, file] if cmd == "utxo-snapshot" && flag == "--height" => {
+            match height.parse() {
+                Ok(height) => utxo_snapshot(Some(height), file, data_dir, genesis_spec),
+                Err(_) => {
+                    println!("usage: miner_node utxo-snapshot [--height H] <file>");
+                    2
+                }
+            }
+        }
+        [cmd, file] if cmd == "utxo-verify" => utxo_verify(file, data_dir, genesis_spec),
+        [cmd, rest @ ..] if cmd == "sign-upinfo" => match parse_upinfo_args(rest) {
+            Some(upinfo_args) => sign_upinfo(upinfo_args, data_dir, genesis_spec),
+            None => {
+                println!("usage: miner_node sign-upinfo [--expires H] [--fee F] <upinfo>");
+                2
+            }
+        },
+        [cmd, rest @ ..] if cmd == "sign-transfer" => match parse_transfer_args(rest) {
+            Some(transfer_args) => sign_transfer(transfer_args, data_dir, genesis_spec),
+            None => {
+                println!("usage: miner_node sign-transfer [--fee F] [--nonce N] <to> <amount>");
+                2
+            }
+        },
+        [cmd, height, entry] if cmd == "prove" => prove(height, entry, data_dir, genesis_spec),
+        [cmd, rest @ ..] if cmd == "export" => match parse_export_args(rest) {
+            Some(export_args) => export(export_args, data_dir, genesis_spec),
+            None => {
+                println!(
+                    "usage: miner_node export [--from H1] [--to H2] [--format json|bin] <file>"
+                );
+                2
+            }
+        },
+        _ => {
+            println!("usage: miner_node [verify | import <file> | export [options] <file> | prove <height> <index | upinfo> | mined [public key] | balance [public key] | utxo-snapshot [--height H] <file> | utxo-verify <file> | sign-upinfo [--expires H] [--fee F] <upinfo> | sign-transfer [--fee F] [--nonce N] <to> <amount>]");
+            2
+        }
+    }
+}
+
+// 只读地验证块文件中存着的每个块，不改动磁盘上的数据。Chain::open会截掉验证失败的块，所以这里不能用它
+fn verify(data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let stored = match BlockStore::read_all(Path::new(data_dir).join("blocks.dat")) {
+        Ok(stored) => stored,
+        Err(e) => {
+            println!("⛔无法读取块文件:{}", e);
+            return 1;
+        }
+    };
+    let mut chain = Chain::from_spec(genesis_spec).expect("can build genesis block");
+    match chain.verify_all(stored) {
+        Ok(count) => {
+            println!(
+                "✅块文件中的{}个块全部验证通过，主链高度为{}",
+                count + 1,
+                chain.last_block().height
+            );
+            0
+        }
+        Err((height, e)) => {
+            println!("⛔高度为{}的块验证失败:{}", height, e);
+            1
+        }
+    }
+}
+
+fn import(file: &str, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let reader = match archive::open_blocks(file) {
+        Ok(reader) => reader,
+        Err(e) => {
+            println!("⛔无法读取{}:{}", file, e);
+            return 1;
+        }
+    };
+
+    // 先在内存中从创世块开始边读边验证，遇到第一个不合法的块就停下来报告，全部合法才写到本地。
+    // 导出文件来自别处，和从别的节点收到的块一样按新块检查全部规则
+    let mut read_error = None;
+    let blocks = reader.map_while(|n| n.map_err(|e| read_error = Some(e)).ok());
+    let mut imported = Chain::from_spec(genesis_spec).expect("can build genesis block");
+    if let Err((height, e)) = imported.verify_new(blocks) {
+        println!("⛔高度为{}的块验证失败:{}", height, e);
+        return 1;
+    }
+    if let Some(e) = read_error {
+        println!("⛔无法读取{}:{}", file, e);
+        return 1;
+    }
+
+    let mut chain = Chain::open(data_dir, genesis_spec).expect("can open chain data dir");
+    for block in imported.main_chain().iter().skip(1) {
+        match chain.try_add_a_block(block.clone()) {
+            Ok(_) | Err(BlockValidationError::AlreadyKnown) => {}
+            Err(e) => {
+                println!("⛔高度为{}的块导入失败:{}", block.height, e);
+                return 1;
+            }
+        }
+    }
+    println!(
+        "✅导入完成，导出文件中的主链高度为{}，本地主链高度为{}",
+        imported.last_block().height,
+        chain.last_block().height
+    );
+    0
+}
+
+struct ExportArgs {
+    from: usize,
+    to: Option<usize>,
+    format: ArchiveFormat,
+    file: String,
+}
+
+fn parse_export_args(args: &[String]) -> Option<ExportArgs> {
+    let mut export_args = ExportArgs {
+        from: 0,
+        to: None,
+        format: ArchiveFormat::Json,
+        file: String::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => export_args.from = args.next()?.parse().ok()?,
+            "--to" => export_args.to = Some(args.next()?.parse().ok()?),
+            "--format" => export_args.format = ArchiveFormat::parse(args.next()?)?,
+            file if export_args.file.is_empty() && !file.starts_with("--") => {
+                export_args.file = file.to_string()
+            }
+            _ => return None,
+        }
+    }
+    if export_args.file.is_empty() {
+        return None;
+    }
+    Some(export_args)
+}
+
+fn export(export_args: ExportArgs, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let tip = chain.last_block().height;
+    let to = export_args.to.unwrap_or(tip);
+    let blocks = match chain.range(export_args.from, to) {
+        Some(blocks) => blocks,
+        None => {
+            println!(
+                "⛔导出范围{}..={}不合法，本地主链高度为{}",
+                export_args.from, to, tip
+            );
+            return 1;
+        }
+    };
+    match archive::write_blocks(&export_args.file, export_args.format, blocks) {
+        Ok(count) => {
+            println!("✅已把{}个块导出到{}", count, export_args.file);
+            0
+        }
+        Err(e) => {
+            println!("⛔导出失败:{}", e);
+            1
+        }
+    }
+}
+
+fn prove(height: &str, entry: &str, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let block = match height.parse().ok().and_then(|n| chain.get_by_height(n)) {
+        Some(block) => block,
+        None => {
+            println!("⛔主链上没有高度为{}的块", height);
+            return 1;
+        }
+    };
+    let block_hash = block.hash();
+    let proof = match entry.parse() {
+        Ok(index) => chain.inclusion_proof(&block_hash, index),
+        Err(_) => chain.inclusion_proof_of(&block_hash, entry),
+    };
+    let proof = match proof {
+        Some(proof) => proof,
+        None => {
+            println!("⛔高度为{}的块中没有upinfo {}", height, entry);
+            return 1;
+        }
+    };
+
+    // 输出之前先自己用块头验一遍
+    if !proof.verify(&block.header()) {
+        println!("⛔生成的证明没有通过验证");
+        return 1;
+    }
+    println!(
+        "{}",
+        serde_json::to_string(&proof).expect("can jsonify proof")
+    );
+    0
+}
+
+fn mined(public_key: &[u8], data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let heights = chain.blocks_mined_by(public_key);
+    let reward: u64 = heights
+        .iter()
+        .map(|n| chain.get_by_height(*n).unwrap().coinbase.reward)
+        .sum();
+    println!("⛏️{}", hex::encode(public_key));
+    println!("主链上共挖出{}个块，奖励合计{}", heights.len(), reward);
+    println!("块高度:{:?}", heights);
+    0
+}
+
+fn balance(public_key: &[u8], data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    println!("💰{}", hex::encode(public_key));
+    match chain.ledger() {
+        Ledger::Account(ledger) => {
+            let account = ledger.account(public_key);
+            println!("余额:{}  nonce:{}", account.balance, account.nonce);
+        }
+        Ledger::Utxo(_) => println!("余额:{}", chain.balance(public_key)),
+    }
+    0
+}
+
+fn utxo_snapshot(
+    height: Option<usize>,
+    file: &str,
+    data_dir: &str,
+    genesis_spec: &GenesisSpec,
+) -> i32 {
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let height = height.unwrap_or(chain.last_block().height);
+    let snapshot = match chain.utxo_snapshot_at(height) {
+        Some(snapshot) => snapshot,
+        None => {
+            println!("⛔本链不是UTXO模型，或者主链上没有高度为{}的块", height);
+            return 1;
+        }
+    };
+    let json = serde_json::to_vec(&snapshot).expect("can jsonify snapshot");
+    match fs::write(file, json) {
+        Ok(()) => {
+            println!(
+                "✅已把高度{}处的{}个UTXO写到{}",
+                height,
+                snapshot.outputs.len(),
+                file
+            );
+            0
+        }
+        Err(e) => {
+            println!("⛔写入{}失败:{}", file, e);
+            1
+        }
+    }
+}
+
+fn utxo_verify(file: &str, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let snapshot: UtxoSnapshot = match fs::read(file)
+        .map_err(|e| e.to_string())
+        .and_then(|n| serde_json::from_slice(&n).map_err(|e| e.to_string()))
+    {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            println!("⛔无法读取{}:{}", file, e);
+            return 1;
+        }
+    };
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let restored = match UtxoLedger::restore(&snapshot, chain.chain_id()) {
+        Some(restored) => restored,
+        None => {
+            println!("⛔快照中有重复的UTXO");
+            return 1;
+        }
+    };
+
+    let expected = chain.utxo_snapshot_at(snapshot.height);
+    if expected == Some(restored.snapshot(snapshot.height, snapshot.tip_hash)) {
+        println!("✅快照和本地主链高度{}处的UTXO集合一致", snapshot.height);
+        0
+    } else {
+        println!("⛔快照和本地主链高度{}处的UTXO集合不一致", snapshot.height);
+        1
+    }
+}
+
+struct UpinfoArgs {
+    upinfo: String,
+    expires_at: Option<usize>,
+    fee: u64,
+}
+
+fn parse_upinfo_args(args: &[String]) -> Option<UpinfoArgs> {
+    let mut upinfo_args = UpinfoArgs {
+        upinfo: String::new(),
+        expires_at: None,
+        fee: 0,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--expires" => upinfo_args.expires_at = Some(args.next()?.parse().ok()?),
+            "--fee" => upinfo_args.fee = args.next()?.parse().ok()?,
+            upinfo if upinfo_args.upinfo.is_empty() && !upinfo.starts_with("--") => {
+                upinfo_args.upinfo = upinfo.to_string()
+            }
+            _ => return None,
+        }
+    }
+    if upinfo_args.upinfo.is_empty() {
+        return None;
+    }
+    Some(upinfo_args)
+}
+
+fn sign_upinfo(upinfo_args: UpinfoArgs, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let keypair = match keystore::load_or_create(data_dir) {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            println!("⛔无法读取矿工密钥:{}", e);
+            return 1;
+        }
+    };
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    if upinfo_args.fee > 0 && matches!(chain.ledger(), Ledger::Utxo(_)) {
+        println!("⛔UTXO模型的链上upinfo不能带手续费");
+        return 2;
+    }
+    let expires_at = upinfo_args
+        .expires_at
+        .unwrap_or(chain.last_block().height + UPINFO_MAX_LIFETIME / 2);
+    let entry = SignedEntry::new_signed(
+        upinfo_args.upinfo,
+        expires_at,
+        upinfo_args.fee,
+        &chain.chain_id(),
+        &keypair,
+    );
+    println!(
+        "{}",
+        serde_json::to_string(&NewUPINFO::from(entry)).expect("can jsonify upinfo")
+    );
+    0
+}
+
+struct TransferArgs {
+    to: Vec<u8>,
+    amount: u64,
+    fee: u64,
+    nonce: Option<u64>,
+}
+
+fn parse_transfer_args(args: &[String]) -> Option<TransferArgs> {
+    let mut transfer_args = TransferArgs {
+        to: vec![],
+        amount: 0,
+        fee: 0,
+        nonce: None,
+    };
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fee" => transfer_args.fee = args.next()?.parse().ok()?,
+            "--nonce" => transfer_args.nonce = Some(args.next()?.parse().ok()?),
+            arg if !arg.starts_with("--") => positional.push(arg),
+            _ => return None,
+        }
+    }
+    match positional[..] {
+        [to, amount] => {
+            transfer_args.to = hex::decode(to).ok()?;
+            transfer_args.amount = amount.parse().ok()?;
+        }
+        _ => return None,
+    }
+    Some(transfer_args)
+}
+
+fn sign_transfer(transfer_args: TransferArgs, data_dir: &str, genesis_spec: &GenesisSpec) -> i32 {
+    let keypair = match keystore::load_or_create(data_dir) {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            println!("⛔无法读取矿工密钥:{}", e);
+            return 1;
+        }
+    };
+    let chain = Chain::open_read_only(data_dir, genesis_spec).expect("can read chain data dir");
+    let ledger = match chain.ledger() {
+        Ledger::Account(ledger) => ledger,
+        Ledger::Utxo(ledger) => return sign_utxo_transfer(transfer_args, ledger, &chain, &keypair),
+    };
+    let nonce = transfer_args
+        .nonce
+        .unwrap_or_else(|| ledger.account(keypair.public.as_bytes()).nonce);
+    let tx = Transaction::new_signed(
+        transfer_args.to,
+        transfer_args.amount,
+        transfer_args.fee,
+        nonce,
+        &chain.chain_id(),
+        &keypair,
+    );
+    println!(
+        "{}",
+        serde_json::to_string(&tx).expect("can jsonify transaction")
+    );
+    0
+}
+
+fn sign_utxo_transfer(
+    transfer_args: TransferArgs,
+    ledger: &UtxoLedger,
+    chain: &Chain,
+    keypair: &ed25519_dalek::Keypair,
+) -> i32 {
+    if transfer_args.nonce.is_some() {
+        println!("⛔UTXO模型的交易没有nonce");
+        return 2;
+    }
+    let needed = match transfer_args.amount.checked_add(transfer_args.fee) {
+        Some(needed) => needed,
+        None => {
+            println!("⛔金额加手续费溢出了");
+            return 2;
+        }
+    };
+    let own_key = keypair.public.to_bytes().to_vec();
+    let mut inputs = vec![];
+    let mut input_sum: u64 = 0;
+    for (outpoint, output) in ledger.outputs_of(&own_key) {
+        if input_sum >= needed {
+            break;
+        }
+        inputs.push(outpoint);
+        input_sum = input_sum.saturating_add(output.amount);
+    }
+    if input_sum < needed {
+        println!("⛔余额不足:{}，需要{}", input_sum, needed);
+        return 1;
+    }
+
+    let mut outputs = vec![TxOut {
+        public_key: transfer_args.to,
+        amount: transfer_args.amount,
+    }];
+    if input_sum > needed {
+        outputs.push(TxOut {
+            public_key: own_key,
+            amount: input_sum - needed,
+        });
+    }
+    let tx = UtxoTransaction::new_signed(inputs, outputs, &chain.chain_id(), keypair);
+    println!(
+        "{}",
+        serde_json::to_string(&tx).expect("can jsonify transaction")
+    );
+    0
+}
@@ -0,0 +1,15 @@
+// 时间来源。Chain验证块的时间戳时通过它获取当前时间，需要模拟时间时可以换成别的实现
+use chrono::Utc;
+
+pub trait Clock: Send + Sync {
+    // 当前的UNIX时间戳，单位毫秒
+    fn now_millis(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
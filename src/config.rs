@@ -0,0 +1,44 @@
+// 节点配置。通过环境变量RUNCHAIN_CONFIG指定一个json文件，没有指定时读data_dir/node.json，文件也不存在就全用默认值。
+// 每个字段都可以省略，省略的字段用默认值，比如只调打包间隔：
+//      {"template": {"max_interval_ms": 10000}}
+// 在共用的机器上挖矿时可以限制线程数和CPU占用：
+//      {"mining": {"threads": 2, "cpu_percent": 50}}
+use crate::pow::MiningConfig;
+use crate::template::TemplateConfig;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    pub template: TemplateConfig,
+    pub mining: MiningConfig,
+}
+
+impl NodeConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let config: NodeConfig = serde_json::from_slice(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config
+            .template
+            .validate()
+            .and_then(|_| config.mining.validate())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+
+    pub fn load(data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        if let Ok(path) = std::env::var("RUNCHAIN_CONFIG") {
+            return NodeConfig::from_file(path);
+        }
+        let path = data_dir.as_ref().join("node.json");
+        if path.exists() {
+            NodeConfig::from_file(path)
+        } else {
+            Ok(NodeConfig::default())
+        }
+    }
+}
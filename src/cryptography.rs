@@ -1,25 +1,62 @@
 use ed25519_dalek::{Signer, Verifier};
 
-pub fn sign<'a, T>(original: &[u8], signing_key: &T) -> Vec<u8>
+// 链标识，就是创世块的哈希。签名时把它写进签名原文，给一个网络做的签名拿到另一个网络上验证不过
+pub type ChainId = [u8; 32];
+
+// 签名原文的固定前缀，和别的程序用同一把密钥签的东西区分开
+const SIGNING_PREFIX: &[u8] = b"RUNCHAIN";
+
+// 被签名的消息类型，同一把密钥给一种消息做的签名不能冒充另一种消息
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageTag {
+    Upinfo = 1,
+    Transaction = 2,
+    UtxoTransaction = 3,
+}
+
+// 实际被签名的字节：SIGNING_PREFIX | chain_id(32) | tag(1) | message。钱包和矿工都必须用这个格式
+fn signing_envelope(chain_id: &ChainId, tag: MessageTag, message: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SIGNING_PREFIX.len() + 33 + message.len());
+    buf.extend_from_slice(SIGNING_PREFIX);
+    buf.extend_from_slice(chain_id);
+    buf.push(tag as u8);
+    buf.extend_from_slice(message);
+    buf
+}
+
+pub fn sign<T>(chain_id: &ChainId, tag: MessageTag, message: &[u8], signing_key: &T) -> Vec<u8>
 where
     T: Signer<ed25519::Signature>,
 {
-    let signature: &[u8; 64] = &signing_key.sign(&original).into();
-    let result: Vec<u8> = signature.iter().map(|n| *n).collect();
-    assert!(result.len() == 64);
-    result
+    let envelope = signing_envelope(chain_id, tag, message);
+    let signature: [u8; 64] = signing_key.sign(&envelope).into();
+    signature.to_vec()
 }
 
-pub fn verify(public_key: &Vec<u8>, original_message: &String, signature: &Vec<u8>) -> bool {
-    assert!(signature.len() == 64);
-    // &Vec<u8> -> &[u8]-> [u8;64]-> ed25519_dalek::Signature
-    let signature: &[u8] = &*signature; // as_ref
-    let signature: [u8; 64] = signature.try_into().unwrap();
-    let signature: ed25519_dalek::Signature = signature.into();
+// 公钥和签名可能来自网络上的任何节点，格式不对时直接判为验证失败而不是panic
+pub fn verify(
+    public_key: &[u8],
+    chain_id: &ChainId,
+    tag: MessageTag,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    // &[u8] -> ed25519_dalek::Signature
+    let signature = match ed25519_dalek::Signature::try_from(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
 
-    // 思路和上面类似，&Vec<u8> -> &[u8] -> public_key
-    let public_key = ed25519_dalek::PublicKey::from_bytes(public_key).unwrap();
+    // 思路和上面类似，&[u8] -> public_key
+    let public_key = match ed25519_dalek::PublicKey::from_bytes(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
     public_key
-        .verify(original_message.as_bytes(), &signature)
+        .verify(&signing_envelope(chain_id, tag, message), &signature)
         .is_ok()
 }
+
+pub fn is_valid_public_key(public_key: &[u8]) -> bool {
+    ed25519_dalek::PublicKey::from_bytes(public_key).is_ok()
+}
@@ -0,0 +1,180 @@
+// 难度目标的压缩格式(和比特币的nBits类似)：最高字节是指数e，低3字节是尾数m，
+//      target = m * 256^(e-3)
+// 块哈希按大端序看成256位整数，小于等于target才算合格
+use crate::block::Hash;
+
+pub fn target_from_bits(bits: u32) -> [u8; 32] {
+    let exponent = (bits >> 24) as isize;
+    let mantissa = (bits & 0x00ff_ffff).to_be_bytes();
+    let mut target = [0u8; 32];
+    for i in 0..3 {
+        let pos = 32 - exponent + i as isize;
+        if (0..32).contains(&pos) {
+            target[pos as usize] = mantissa[i + 1];
+        }
+    }
+    target
+}
+
+pub fn hash_meets_target(hash: &Hash, bits: u32) -> bool {
+    // 两个都是32字节的大端序整数，直接按字节序比较就是按数值比较
+    hash[..] <= target_from_bits(bits)[..]
+}
+
+// 能不能当作难度上限：尾数是规范化的(最高字节不为0，自然也不为0)，目标值不超过256位，低位也没有被截掉。
+// retarget按(指数,尾数)比较大小，上限不规范化的话比较结果不对
+pub fn is_valid_limit(bits: u32) -> bool {
+    let exponent = bits >> 24;
+    (3..=32).contains(&exponent) && bits & 0x00ff_0000 != 0
+}
+
+// 一个目标值对应的工作量，约等于2^256 / target
+pub fn work_from_bits(bits: u32) -> u128 {
+    let exponent = (bits >> 24) as i32;
+    let mantissa = (bits & 0x00ff_ffff) as u128;
+    if mantissa == 0 {
+        return u128::MAX;
+    }
+    // 2^256 / (m * 2^(8(e-3))) = 2^(280-8e) / m
+    let shift = 280 - 8 * exponent;
+    if shift < 0 {
+        return 1;
+    }
+    if shift <= 127 {
+        return ((1u128 << shift) / mantissa).max(1);
+    }
+    let base = (1u128 << 127) / mantissa;
+    let extra = (shift - 127) as u32;
+    if base.leading_zeros() < extra {
+        return u128::MAX;
+    }
+    base << extra
+}
+
+// 按实际用时和期望用时的比例调整目标值，单次调整幅度限制在4倍以内，且不能比难度上限limit_bits更容易
+pub fn retarget(
+    bits: u32,
+    actual_timespan_ms: i64,
+    expected_timespan_ms: i64,
+    limit_bits: u32,
+) -> u32 {
+    let actual = actual_timespan_ms.clamp(expected_timespan_ms / 4, expected_timespan_ms * 4);
+
+    let mut exponent = bits >> 24;
+    let mut mantissa = (bits & 0x00ff_ffff) as u128 * actual as u128 / expected_timespan_ms as u128;
+
+    // 规范化，让尾数的最高字节不为0，这样(指数,尾数)的大小关系就和目标值的大小关系一致
+    while mantissa > 0x00ff_ffff {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    while mantissa < 0x0001_0000 && mantissa != 0 && exponent > 3 {
+        mantissa <<= 8;
+        exponent -= 1;
+    }
+
+    let limit_exponent = limit_bits >> 24;
+    let limit_mantissa = limit_bits & 0x00ff_ffff;
+    if (exponent, mantissa as u32) > (limit_exponent, limit_mantissa) {
+        return limit_bits;
+    }
+    (exponent << 24) | mantissa as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::POW_LIMIT_BITS;
+
+    #[test]
+    fn target_from_bits_places_the_mantissa() {
+        let target = target_from_bits(0x1dff_ffff);
+        assert_eq!(target[..3], [0, 0, 0]);
+        assert_eq!(target[3..6], [0xff, 0xff, 0xff]);
+        assert!(target[6..].iter().all(|n| *n == 0));
+
+        let target = target_from_bits(0x0300_1234);
+        assert!(target[..29].iter().all(|n| *n == 0));
+        assert_eq!(target[29..], [0x00, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn hash_meets_target_is_inclusive() {
+        let target = target_from_bits(0x1dff_ffff);
+        assert!(hash_meets_target(&target, 0x1dff_ffff));
+        let mut above = target;
+        above[6] = 1;
+        assert!(!hash_meets_target(&above, 0x1dff_ffff));
+        assert!(hash_meets_target(&[0; 32], 0x1dff_ffff));
+    }
+
+    #[test]
+    fn work_grows_as_the_target_shrinks() {
+        // 2^256 / (0xffffff * 2^208)，大约是2^24
+        assert_eq!(work_from_bits(0x1dff_ffff), (1u128 << 48) / 0xff_ffff);
+        assert_eq!(work_from_bits(0x207f_ffff), 2);
+        assert!(work_from_bits(0x1d00_ffff) > work_from_bits(0x1dff_ffff));
+        assert!(work_from_bits(0x1c7f_ffff) > work_from_bits(0x1cff_ffff));
+        assert_eq!(work_from_bits(0x1d00_0000), u128::MAX);
+    }
+
+    #[test]
+    fn limits_must_be_normalized_and_fit() {
+        assert!(is_valid_limit(POW_LIMIT_BITS));
+        assert!(is_valid_limit(0x207f_ffff));
+        assert!(is_valid_limit(0x20ff_ffff));
+        assert!(is_valid_limit(0x0301_0000));
+        // 尾数为0，或者最高字节为0
+        assert!(!is_valid_limit(0x1d00_0000));
+        assert!(!is_valid_limit(0x1d00_ffff));
+        // 目标值超过256位，或者低位被截掉
+        assert!(!is_valid_limit(0x217f_ffff));
+        assert!(!is_valid_limit(0x027f_ffff));
+    }
+
+    #[test]
+    fn retarget_keeps_bits_when_on_schedule() {
+        assert_eq!(
+            retarget(0x1c7f_ffff, 1000, 1000, POW_LIMIT_BITS),
+            0x1c7f_ffff
+        );
+    }
+
+    #[test]
+    fn retarget_follows_the_actual_timespan() {
+        // 用时减半，目标值减半，难度翻倍
+        assert_eq!(
+            retarget(0x1c7f_ffff, 500, 1000, POW_LIMIT_BITS),
+            0x1c3f_ffff
+        );
+        // 用时翻倍，目标值翻倍
+        assert_eq!(
+            retarget(0x1c3f_ffff, 2000, 1000, POW_LIMIT_BITS),
+            0x1c7f_fffe
+        );
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_four_times() {
+        assert_eq!(
+            retarget(0x1c7f_ffff, 1, 1000, POW_LIMIT_BITS),
+            retarget(0x1c7f_ffff, 250, 1000, POW_LIMIT_BITS)
+        );
+        assert_eq!(
+            retarget(0x1b7f_ffff, 1_000_000, 1000, POW_LIMIT_BITS),
+            retarget(0x1b7f_ffff, 4000, 1000, POW_LIMIT_BITS)
+        );
+    }
+
+    #[test]
+    fn retarget_never_exceeds_the_limit() {
+        assert_eq!(
+            retarget(POW_LIMIT_BITS, 4000, 1000, POW_LIMIT_BITS),
+            POW_LIMIT_BITS
+        );
+        assert_eq!(retarget(0x207f_ffff, 4000, 1000, 0x207f_ffff), 0x207f_ffff);
+        // 结果要规范化，尾数的最高字节不为0
+        let bits = retarget(0x1d01_0000, 250, 1000, POW_LIMIT_BITS);
+        assert!(bits & 0x00ff_0000 != 0);
+    }
+}
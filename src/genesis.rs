@@ -0,0 +1,204 @@
+// 创世块规格。所有节点必须用同一份规格生成创世块，ChainInfo中的genesis_hash才能对得上
+// 默认使用编译进来的RUNCHAINNET规格，也可以通过环境变量RUNCHAIN_GENESIS指定一个json文件
+use crate::block::{Block, Coinbase, SignedEntry};
+use crate::difficulty;
+use crate::ledger::LedgerKind;
+use crate::protocol::{HALVING_INTERVAL, INITIAL_BLOCK_REWARD, POW_LIMIT_BITS};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenesisSpec {
+    pub timestamp: i64, // UNIX时间戳，单位毫秒
+    pub upinfo: Vec<String>,
+    pub merkle_root: String, // hex编码，必须和upinfo、issuance、ledger算出来的默克尔根一致
+    #[serde(default)]
+    pub issuance: Issuance,
+    // 账本模型，account或者utxo，见ledger.rs
+    #[serde(default)]
+    pub ledger: LedgerKind,
+    // 难度上限，也就是创世块的难度，之后的块调整难度时不能比它更容易。测试网可以设得很低，省得挖块太慢
+    #[serde(default = "default_pow_limit_bits")]
+    pub pow_limit_bits: u32,
+}
+
+fn default_pow_limit_bits() -> u32 {
+    POW_LIMIT_BITS
+}
+
+// 出块奖励的发行计划
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Issuance {
+    pub initial_reward: u64,
+    pub halving_interval: usize,
+}
+
+impl Default for Issuance {
+    fn default() -> Self {
+        Issuance {
+            initial_reward: INITIAL_BLOCK_REWARD,
+            halving_interval: HALVING_INTERVAL,
+        }
+    }
+}
+
+impl Issuance {
+    // 高度为height的块的coinbase中应该填的奖励。创世块没有奖励
+    pub fn reward_at(&self, height: usize) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+}
+
+impl GenesisSpec {
+    pub fn runchainnet() -> Self {
+        GenesisSpec {
+            timestamp: 1653004800000, // 2022-05-20 00:00:00 UTC
+            upinfo: vec![
+                "This is RunChain's first block".to_string(),
+                "Tonight,you are so beautiful.".to_string(),
+                "I want you more than any other time.".to_string(),
+            ],
+            merkle_root: "685af8ba56d9c70f11afa1ba1144333805ad3abeb2cdf7addb7c0fb9b5366b61"
+                .to_string(),
+            issuance: Issuance::default(),
+            ledger: LedgerKind::Account,
+            pow_limit_bits: POW_LIMIT_BITS,
+        }
+    }
+
+    // 读进来就检查一遍，规格有问题的话节点启动时就报错
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let spec: GenesisSpec = serde_json::from_slice(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        spec.to_block()?;
+        Ok(spec)
+    }
+
+    pub fn load() -> io::Result<Self> {
+        match std::env::var("RUNCHAIN_GENESIS") {
+            Ok(path) => GenesisSpec::from_file(path),
+            Err(_) => Ok(GenesisSpec::runchainnet()),
+        }
+    }
+
+    pub fn to_block(&self) -> io::Result<Block> {
+        if !difficulty::is_valid_limit(self.pow_limit_bits) {
+            return Err(invalid_spec(
+                "genesis pow_limit_bits must have a normalized nonzero mantissa and a target within 256 bits",
+            ));
+        }
+        // 创世块的upinfo没有人签名，公钥和签名都为空
+        let upinfo: Vec<SignedEntry> = self
+            .upinfo
+            .iter()
+            .map(|n| SignedEntry {
+                upinfo: n.clone(),
+                expires_at: 0,
+                fee: 0,
+                public_key: vec![],
+                signature: vec![],
+            })
+            .collect();
+        let mut block = Block {
+            height: 0,
+            previous_hash: [0; 32],
+            timestamp: self.timestamp,
+            merkle_root: [0; 32],
+            bits: self.pow_limit_bits,
+            nonce: 0,
+            coinbase: Coinbase {
+                public_key: self.committed_params(),
+                reward: 0,
+            },
+            upinfo,
+            transactions: vec![],
+            utxo_transactions: vec![],
+        };
+        block.merkle_root = block.compute_merkle_root();
+
+        if hex::encode(block.merkle_root) != self.merkle_root.to_lowercase() {
+            return Err(invalid_spec(
+                "genesis merkle root does not match its upinfo and parameters",
+            ));
+        }
+        Ok(block)
+    }
+
+    // 创世块没有矿工，coinbase的public_key就用来承诺这条链的参数：
+    //      ledger(1，account为0、utxo为1) | initial_reward(8) | halving_interval(8)，整数大端序
+    // 它在默克尔根里，所以参数不同的网络创世块哈希(也就是ChainId)一定不同
+    fn committed_params(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(17);
+        buf.push(match self.ledger {
+            LedgerKind::Account => 0,
+            LedgerKind::Utxo => 1,
+        });
+        buf.extend_from_slice(&self.issuance.initial_reward.to_be_bytes());
+        buf.extend_from_slice(&(self.issuance.halving_interval as u64).to_be_bytes());
+        buf
+    }
+}
+
+fn invalid_spec(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runchainnet_builds() {
+        let block = GenesisSpec::runchainnet().to_block().unwrap();
+        assert_eq!(block.height, 0);
+        assert_eq!(block.upinfo.len(), 3);
+    }
+
+    #[test]
+    fn ledger_and_issuance_are_committed() {
+        let spec = GenesisSpec::runchainnet();
+        let other_ledger = GenesisSpec {
+            ledger: LedgerKind::Utxo,
+            ..spec.clone()
+        };
+        let other_issuance = GenesisSpec {
+            issuance: Issuance {
+                initial_reward: spec.issuance.initial_reward * 2,
+                ..spec.issuance
+            },
+            ..spec.clone()
+        };
+        for changed in [other_ledger, other_issuance] {
+            assert!(changed.to_block().is_err());
+        }
+    }
+
+    #[test]
+    fn pow_limit_bits_is_validated() {
+        for pow_limit_bits in [0x1d00_0000, 0x1d00_ffff, 0x2200_0001, 0x0100_0001] {
+            let spec = GenesisSpec {
+                pow_limit_bits,
+                ..GenesisSpec::runchainnet()
+            };
+            assert!(spec.to_block().is_err(), "{:#x}", pow_limit_bits);
+        }
+        let dir = std::env::temp_dir().join(format!("runchain-genesis-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("genesis.json");
+        let mut spec = serde_json::to_value(GenesisSpec::runchainnet()).unwrap();
+        spec["pow_limit_bits"] = 0x1d00_0000.into();
+        fs::write(&path, spec.to_string()).unwrap();
+        assert!(GenesisSpec::from_file(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
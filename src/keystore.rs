@@ -0,0 +1,40 @@
+// 矿工自己的密钥对，出块奖励记在它的公钥名下。保存在data_dir/miner.key中(64字节的私钥+公钥，hex编码)，第一次运行时生成
+use ed25519_dalek::Keypair;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub fn load_or_create(data_dir: impl AsRef<Path>) -> io::Result<Keypair> {
+    let path = data_dir.as_ref().join("miner.key");
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        let bytes = hex::decode(content.trim())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        return Keypair::from_bytes(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+
+    fs::create_dir_all(&data_dir)?;
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+    write_private(&path, hex::encode(keypair.to_bytes()).as_bytes())?;
+    println!("🔑已生成新的矿工密钥，保存在{}", path.display());
+    Ok(keypair)
+}
+
+// 私钥文件只让自己读写
+#[cfg(unix)]
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    fs::write(path, content)
+}
@@ -0,0 +1,485 @@
+// 账本记录主链末尾处每个公钥有多少钱。有两种模型可选，由创世规格中的ledger决定：
+//      account  账户模型，见下面的AccountLedger，块中只能有transactions，upinfo可以带手续费
+//      utxo     UTXO模型，见utxo.rs，块中只能有utxo_transactions，upinfo的手续费必须为0
+// Chain在主链上每接上一个块就apply_block一次，重组撤下块时按相反的顺序rollback_block
+use crate::block::{Block, BlockValidationError, SignedEntry};
+use crate::cryptography::ChainId;
+use crate::transaction::Transaction;
+use crate::utxo::UtxoLedger;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerKind {
+    #[default]
+    Account,
+    Utxo,
+}
+
+#[derive(Clone, Debug)]
+pub enum Ledger {
+    Account(AccountLedger),
+    Utxo(UtxoLedger),
+}
+
+impl Ledger {
+    pub fn new(kind: LedgerKind, chain_id: ChainId) -> Self {
+        match kind {
+            LedgerKind::Account => Ledger::Account(AccountLedger::default()),
+            LedgerKind::Utxo => Ledger::Utxo(UtxoLedger::new(chain_id)),
+        }
+    }
+
+    pub fn balance(&self, public_key: &[u8]) -> u64 {
+        match self {
+            Ledger::Account(ledger) => ledger.account(public_key).balance,
+            Ledger::Utxo(ledger) => ledger.balance(public_key),
+        }
+    }
+
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), BlockValidationError> {
+        match self {
+            Ledger::Account(ledger) => ledger.apply_block(block),
+            Ledger::Utxo(ledger) => {
+                // UTXO模型没有账户可以扣upinfo的手续费
+                if let Some(index) = block.upinfo.iter().position(|n| n.fee > 0) {
+                    return Err(BlockValidationError::BadUpinfoFee {
+                        index,
+                        reason: TransactionError::WrongModel,
+                    });
+                }
+                ledger.apply_block(block).map_err(|(index, reason)| {
+                    BlockValidationError::BadTransaction { index, reason }
+                })
+            }
+        }
+    }
+
+    pub fn rollback_block(&mut self, block: &Block) {
+        match self {
+            Ledger::Account(ledger) => ledger.rollback_block(block),
+            Ledger::Utxo(ledger) => ledger.rollback_block(block),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64, // 下一笔交易应该使用的nonce
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccountLedger {
+    accounts: HashMap<Vec<u8>, Account>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    BadSignature,
+    BadNonce { expected: u64, got: u64 },
+    InsufficientBalance { balance: u64, needed: u64 },
+    Overflow,
+    WrongModel,                    // 交易的类型和本链的账本模型不一致
+    NoInputs,                      // UTXO交易没有输入
+    UnknownInput { input: usize }, // 输入引用的输出不存在或者已经被花掉了
+    DoubleSpend { input: usize },  // 这个输入花的输出已经被同一个块中前面的输入花过了
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::BadSignature => write!(f, "invalid signature"),
+            TransactionError::BadNonce { expected, got } => {
+                write!(f, "invalid nonce {}, expected {}", got, expected)
+            }
+            TransactionError::InsufficientBalance { balance, needed } => {
+                write!(f, "insufficient balance {}, needs {}", balance, needed)
+            }
+            TransactionError::Overflow => write!(f, "amount overflows"),
+            TransactionError::WrongModel => {
+                write!(f, "transaction type does not match the ledger model")
+            }
+            TransactionError::NoInputs => write!(f, "transaction has no inputs"),
+            TransactionError::UnknownInput { input } => {
+                write!(
+                    f,
+                    "input {} spends a missing or already spent output",
+                    input
+                )
+            }
+            TransactionError::DoubleSpend { input } => {
+                write!(f, "input {} spends the same output twice", input)
+            }
+        }
+    }
+}
+
+impl AccountLedger {
+    // 没出现过的公钥就是余额为0的空账户
+    pub fn account(&self, public_key: &[u8]) -> Account {
+        self.accounts.get(public_key).copied().unwrap_or_default()
+    }
+
+    // 只检查nonce和余额，签名在验证块的时候已经检查过了。失败时账本不变
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        let sender = self.account(&tx.from);
+        if tx.nonce != sender.nonce {
+            return Err(TransactionError::BadNonce {
+                expected: sender.nonce,
+                got: tx.nonce,
+            });
+        }
+        let needed = tx
+            .amount
+            .checked_add(tx.fee)
+            .ok_or(TransactionError::Overflow)?;
+        if sender.balance < needed {
+            return Err(TransactionError::InsufficientBalance {
+                balance: sender.balance,
+                needed,
+            });
+        }
+        // from和to可能是同一个账户，所以要先扣款再读收款方的余额
+        let receiver_balance = if tx.from == tx.to {
+            sender.balance - needed
+        } else {
+            self.account(&tx.to).balance
+        };
+        receiver_balance
+            .checked_add(tx.amount)
+            .ok_or(TransactionError::Overflow)?;
+
+        self.set(
+            &tx.from,
+            Account {
+                balance: sender.balance - needed,
+                nonce: sender.nonce + 1,
+            },
+        );
+        self.credit(&tx.to, tx.amount);
+        Ok(())
+    }
+
+    // 从upinfo的提交者账户中扣掉它的手续费，余额不够时账本不变
+    pub fn charge_upinfo_fee(&mut self, entry: &SignedEntry) -> Result<(), TransactionError> {
+        if entry.fee == 0 {
+            return Ok(());
+        }
+        let balance = self.account(&entry.public_key).balance;
+        if balance < entry.fee {
+            return Err(TransactionError::InsufficientBalance {
+                balance,
+                needed: entry.fee,
+            });
+        }
+        self.debit(&entry.public_key, entry.fee);
+        Ok(())
+    }
+
+    fn refund_upinfo_fees(&mut self, upinfo: &[SignedEntry]) {
+        for entry in upinfo.iter().filter(|n| n.fee > 0) {
+            self.credit(&entry.public_key, entry.fee);
+        }
+    }
+
+    // 撤销一笔已经apply过的交易
+    fn revert_transaction(&mut self, tx: &Transaction) {
+        self.debit(&tx.to, tx.amount);
+        let sender = self.account(&tx.from);
+        self.set(
+            &tx.from,
+            Account {
+                balance: sender.balance + tx.amount + tx.fee,
+                nonce: sender.nonce - 1,
+            },
+        );
+    }
+
+    // 先扣掉所有upinfo的手续费，再依次执行块中的交易，最后把出块奖励和手续费记给矿工。
+    // 要么整个块都生效，要么账本不变
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), BlockValidationError> {
+        if !block.utxo_transactions.is_empty() {
+            return Err(BlockValidationError::BadTransaction {
+                index: 0,
+                reason: TransactionError::WrongModel,
+            });
+        }
+        for (i, entry) in block.upinfo.iter().enumerate() {
+            if let Err(reason) = self.charge_upinfo_fee(entry) {
+                self.refund_upinfo_fees(&block.upinfo[..i]);
+                return Err(BlockValidationError::BadUpinfoFee { index: i, reason });
+            }
+        }
+        for (i, tx) in block.transactions.iter().enumerate() {
+            if let Err(reason) = self.apply_transaction(tx) {
+                for applied in block.transactions[..i].iter().rev() {
+                    self.revert_transaction(applied);
+                }
+                self.refund_upinfo_fees(&block.upinfo);
+                return Err(BlockValidationError::BadTransaction { index: i, reason });
+            }
+        }
+
+        let miner_income = miner_income(block);
+        let miner_balance = self.account(&block.coinbase.public_key).balance;
+        match miner_income.and_then(|n| miner_balance.checked_add(n)) {
+            Some(_) => {
+                self.credit(&block.coinbase.public_key, miner_income.unwrap());
+                Ok(())
+            }
+            None => {
+                for applied in block.transactions.iter().rev() {
+                    self.revert_transaction(applied);
+                }
+                self.refund_upinfo_fees(&block.upinfo);
+                Err(BlockValidationError::BadTransaction {
+                    index: block.transactions.len(),
+                    reason: TransactionError::Overflow,
+                })
+            }
+        }
+    }
+
+    // 撤销一个已经apply过的块，必须从主链末尾开始一个一个往回撤
+    pub fn rollback_block(&mut self, block: &Block) {
+        self.debit(&block.coinbase.public_key, miner_income(block).unwrap());
+        for tx in block.transactions.iter().rev() {
+            self.revert_transaction(tx);
+        }
+        self.refund_upinfo_fees(&block.upinfo);
+    }
+
+    fn credit(&mut self, public_key: &[u8], amount: u64) {
+        let mut account = self.account(public_key);
+        account.balance += amount;
+        self.set(public_key, account);
+    }
+
+    fn debit(&mut self, public_key: &[u8], amount: u64) {
+        let mut account = self.account(public_key);
+        account.balance -= amount;
+        self.set(public_key, account);
+    }
+
+    // 余额和nonce都是0的账户和不存在没有区别，直接删掉
+    fn set(&mut self, public_key: &[u8], account: Account) {
+        if account == Account::default() {
+            self.accounts.remove(public_key);
+        } else {
+            self.accounts.insert(public_key.to_vec(), account);
+        }
+    }
+}
+
+// 矿工从一个块中拿到的出块奖励加upinfo和交易的手续费
+fn miner_income(block: &Block) -> Option<u64> {
+    let mut fees = block
+        .upinfo
+        .iter()
+        .map(|n| n.fee)
+        .chain(block.transactions.iter().map(|n| n.fee));
+    fees.try_fold(block.coinbase.reward, |sum, fee| sum.checked_add(fee))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::*;
+    use crate::block::Chain;
+    use ed25519_dalek::Keypair;
+
+    fn transfer(
+        chain: &Chain,
+        from: &Keypair,
+        to: &Keypair,
+        amount: u64,
+        nonce: u64,
+    ) -> Transaction {
+        let to = to.public.to_bytes().to_vec();
+        Transaction::new_signed(to, amount, 1, nonce, &chain.chain_id(), from)
+    }
+
+    #[test]
+    fn signed_transfer_verifies_and_moves_coins() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let reward = chain.block_reward(1);
+        let tx = transfer(&chain, &alice, &bob, 10, 0);
+        assert!(tx.verify(&chain.chain_id()));
+        ledger.apply_transaction(&tx).unwrap();
+        assert_eq!(
+            ledger.account(alice.public.as_bytes()),
+            Account {
+                balance: reward - 11,
+                nonce: 1
+            }
+        );
+        assert_eq!(ledger.account(bob.public.as_bytes()).balance, 10);
+    }
+
+    #[test]
+    fn overspend_is_rejected_and_leaves_the_ledger_alone() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let reward = chain.block_reward(1);
+        // 金额刚好是全部余额，再加上1的手续费就不够了
+        let tx = transfer(&chain, &alice, &bob, reward, 0);
+        assert_eq!(
+            ledger.apply_transaction(&tx),
+            Err(TransactionError::InsufficientBalance {
+                balance: reward,
+                needed: reward + 1
+            })
+        );
+        assert_eq!(ledger.account(alice.public.as_bytes()).balance, reward);
+        assert_eq!(ledger.account(alice.public.as_bytes()).nonce, 0);
+        assert_eq!(ledger.account(bob.public.as_bytes()), Account::default());
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let tx = transfer(&chain, &keypair(1), &keypair(2), 10, 0);
+        ledger.apply_transaction(&tx).unwrap();
+        assert_eq!(
+            ledger.apply_transaction(&tx),
+            Err(TransactionError::BadNonce {
+                expected: 1,
+                got: 0
+            })
+        );
+        let skipped = transfer(&chain, &keypair(1), &keypair(2), 10, 2);
+        assert_eq!(
+            ledger.apply_transaction(&skipped),
+            Err(TransactionError::BadNonce {
+                expected: 1,
+                got: 2
+            })
+        );
+        assert_eq!(ledger.account(keypair(2).public.as_bytes()).balance, 10);
+    }
+
+    #[test]
+    fn rollback_undoes_a_block() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, bob, miner) = (keypair(1), keypair(2), keypair(3));
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &miner);
+        block.transactions = vec![
+            transfer(&chain, &alice, &bob, 10, 0),
+            transfer(&chain, &alice, &bob, 20, 1),
+            transfer(&chain, &bob, &alice, 5, 0),
+        ];
+        let before: Vec<_> = [&alice, &bob, &miner]
+            .iter()
+            .map(|n| ledger.account(n.public.as_bytes()))
+            .collect();
+
+        ledger.apply_block(&block).unwrap();
+        assert_eq!(ledger.account(bob.public.as_bytes()).balance, 24);
+        assert_eq!(
+            ledger.account(miner.public.as_bytes()).balance,
+            block.coinbase.reward + 3
+        );
+        ledger.rollback_block(&block);
+        let after: Vec<_> = [&alice, &bob, &miner]
+            .iter()
+            .map(|n| ledger.account(n.public.as_bytes()))
+            .collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn failing_block_leaves_the_ledger_alone() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, bob, miner) = (keypair(1), keypair(2), keypair(3));
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &miner);
+        // 第二笔重放了第一笔的nonce
+        let tx = transfer(&chain, &alice, &bob, 10, 0);
+        block.transactions = vec![tx.clone(), tx];
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err(BlockValidationError::BadTransaction {
+                index: 1,
+                reason: TransactionError::BadNonce {
+                    expected: 1,
+                    got: 0
+                }
+            })
+        );
+        assert_eq!(ledger.account(alice.public.as_bytes()).nonce, 0);
+        assert_eq!(ledger.account(bob.public.as_bytes()), Account::default());
+        assert_eq!(ledger.account(miner.public.as_bytes()), Account::default());
+    }
+
+    fn paid_upinfo(chain: &Chain, from: &Keypair, fee: u64) -> SignedEntry {
+        let expires_at = chain.last_block().height + 10;
+        SignedEntry::new_signed("paid".to_string(), expires_at, fee, &chain.chain_id(), from)
+    }
+
+    #[test]
+    fn upinfo_fee_goes_to_the_miner_and_comes_back_on_rollback() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, miner) = (keypair(1), keypair(3));
+        let reward = chain.block_reward(1);
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &miner);
+        block.upinfo = vec![paid_upinfo(&chain, &alice, 7)];
+
+        ledger.apply_block(&block).unwrap();
+        assert_eq!(ledger.account(alice.public.as_bytes()).balance, reward - 7);
+        // upinfo不占nonce
+        assert_eq!(ledger.account(alice.public.as_bytes()).nonce, 0);
+        assert_eq!(
+            ledger.account(miner.public.as_bytes()).balance,
+            block.coinbase.reward + 7
+        );
+        ledger.rollback_block(&block);
+        assert_eq!(ledger.account(alice.public.as_bytes()).balance, reward);
+        assert_eq!(ledger.account(miner.public.as_bytes()), Account::default());
+    }
+
+    #[test]
+    fn unaffordable_upinfo_fee_rejects_the_block() {
+        let (chain, mut ledger) = funded::<AccountLedger>();
+        let (alice, bob, miner) = (keypair(1), keypair(2), keypair(3));
+        let reward = chain.block_reward(1);
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &miner);
+        // bob的钱要等后面的交易才到账，而upinfo的手续费是在执行交易之前扣的
+        block.upinfo = vec![paid_upinfo(&chain, &alice, 1), paid_upinfo(&chain, &bob, 1)];
+        block.transactions = vec![transfer(&chain, &alice, &bob, 10, 0)];
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err(BlockValidationError::BadUpinfoFee {
+                index: 1,
+                reason: TransactionError::InsufficientBalance {
+                    balance: 0,
+                    needed: 1
+                }
+            })
+        );
+        assert_eq!(ledger.account(alice.public.as_bytes()).balance, reward);
+        assert_eq!(ledger.account(bob.public.as_bytes()), Account::default());
+    }
+
+    #[test]
+    fn utxo_chains_reject_upinfo_fees() {
+        let (chain, _) = regtest_chain(&utxo_regtest_spec());
+        let mut ledger = chain.ledger().clone();
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &keypair(3));
+        block.upinfo = vec![paid_upinfo(&chain, &keypair(1), 1)];
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err(BlockValidationError::BadUpinfoFee {
+                index: 0,
+                reason: TransactionError::WrongModel
+            })
+        );
+    }
+}
@@ -1,5 +1,13 @@
 mod block;
+mod clock;
+mod cryptography;
+mod difficulty;
+mod genesis;
+mod ledger;
 mod p2p;
 mod pow;
 mod protocol;
+mod storage;
+mod transaction;
+mod utxo;
 fn main() {}
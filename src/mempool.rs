@@ -0,0 +1,754 @@
+// 交易池。钱包发来的upinfo和交易先进这里，矿工每一轮从中挑出优先级最高的一批打包。
+// 池中的条目按id去重，条数和字节数都有上限，满了之后先挤掉每字节手续费最低的。
+// 条目要等到包含它的块被接进主链(不管是自己挖的还是别人发来的)才从池中删除，挖矿被打断时不用放回。
+// 退出时和运行中每隔一段时间池中的条目会被保存到data_dir/mempool.json，下次启动时重新验证之后放回池中
+use crate::block::{Block, Chain, Hash, SignedEntry};
+use crate::ledger::{Ledger, TransactionError};
+use crate::protocol::{MessageEvent, NewUPINFO, MAX_BLOCK_BODY_BYTES};
+use crate::transaction::Transaction;
+use crate::utxo::{OutPoint, TxOut, UtxoTransaction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum PoolEntry {
+    Upinfo(NewUPINFO),
+    Transaction(Transaction),
+    UtxoTransaction(UtxoTransaction),
+}
+
+impl PoolEntry {
+    pub fn from_message(message: MessageEvent) -> Option<Self> {
+        match message {
+            MessageEvent::NewUPINFO(n) => Some(PoolEntry::Upinfo(n)),
+            MessageEvent::NewTransaction(n) => Some(PoolEntry::Transaction(n)),
+            MessageEvent::NewUtxoTransaction(n) => Some(PoolEntry::UtxoTransaction(n)),
+            _ => None,
+        }
+    }
+
+    // 块中的全部upinfo和交易，重组时用来把被撤下的块放回池中
+    pub fn all_from_block(block: Block) -> Vec<Self> {
+        let upinfo = block
+            .upinfo
+            .into_iter()
+            .map(|n| PoolEntry::Upinfo(n.into()));
+        let transactions = block.transactions.into_iter().map(PoolEntry::Transaction);
+        let utxo_transactions = block
+            .utxo_transactions
+            .into_iter()
+            .map(PoolEntry::UtxoTransaction);
+        upinfo
+            .chain(transactions)
+            .chain(utxo_transactions)
+            .collect()
+    }
+
+    // 去重用的id，和块中的条目用同一种算法，这样上链之后才能按id删掉
+    pub fn id(&self) -> Hash {
+        match self {
+            PoolEntry::Upinfo(n) => SignedEntry::from(n.clone()).replay_id(),
+            PoolEntry::Transaction(n) => n.hash(),
+            PoolEntry::UtxoTransaction(n) => n.txid(),
+        }
+    }
+
+    // 上链之后在块体中占的字节数
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            PoolEntry::Upinfo(n) => SignedEntry::from(n.clone()).encoded_len(),
+            PoolEntry::Transaction(n) => n.encoded_len(),
+            PoolEntry::UtxoTransaction(n) => n.encoded_len(),
+        }
+    }
+}
+
+// 块中每个条目的id
+fn block_entry_ids(block: &Block) -> Vec<Hash> {
+    block
+        .upinfo
+        .iter()
+        .map(|n| n.replay_id())
+        .chain(block.transactions.iter().map(|n| n.hash()))
+        .chain(block.utxo_transactions.iter().map(|n| n.txid()))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    AlreadyKnown,
+    Included, // upinfo已经在最近的块里上链了
+    BadSignature,
+    Expired,   // upinfo已经过期，或者有效期太远
+    TooLarge,  // 一个条目就超过了块体的大小上限
+    FeeTooLow, // 池满了，而它的每字节手续费不比池中最低的高
+    Invalid(TransactionError),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "already in the mempool"),
+            MempoolError::Included => write!(f, "already included in a recent block"),
+            MempoolError::BadSignature => write!(f, "invalid signature"),
+            MempoolError::Expired => write!(f, "upinfo is expired or not yet includable"),
+            MempoolError::TooLarge => write!(f, "larger than a block body"),
+            MempoolError::FeeTooLow => write!(f, "mempool is full and the fee rate is too low"),
+            MempoolError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// 打包时对每个候选条目的判断
+pub enum Verdict {
+    Include, // 打包进这个块
+    Skip,    // 现在还不能打包(比如nonce在后面的交易)，留在池中
+    Reject,  // 再也不可能上链了，从池中删掉
+}
+
+struct Pending {
+    entry: PoolEntry,
+    fee: u64, // 付给矿工的手续费
+    size: usize,
+    added_at: usize, // 进池时主链末尾的高度
+    seq: u64,        // 进池的顺序，每字节手续费相同时先来的优先
+}
+
+impl Pending {
+    // self的每字节手续费是否比other高，用乘法比较省得算小数
+    fn pays_more_than(&self, other: &Pending) -> bool {
+        self.fee as u128 * other.size as u128 > other.fee as u128 * self.size as u128
+    }
+}
+
+pub struct Mempool {
+    entries: HashMap<Hash, Pending>,
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    expiry_blocks: usize,
+    next_seq: u64,
+}
+
+impl Mempool {
+    pub fn new(max_entries: usize, max_bytes: usize, expiry_blocks: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            bytes: 0,
+            max_entries,
+            max_bytes,
+            expiry_blocks,
+            next_seq: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, id: &Hash) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    pub fn get(&self, id: &Hash) -> Option<&PoolEntry> {
+        self.entries.get(id).map(|n| &n.entry)
+    }
+
+    // 在chain的主链末尾检查签名和能不能上链，算出手续费之后放进池中，返回条目的id
+    pub fn admit(&mut self, entry: PoolEntry, chain: &Chain) -> Result<Hash, MempoolError> {
+        let chain_id = chain.chain_id();
+        let next_height = chain.last_block().height + 1;
+        let fee = match &entry {
+            PoolEntry::Upinfo(n) => {
+                let signed = SignedEntry::from(n.clone());
+                if !signed.verify(&chain_id) {
+                    return Err(MempoolError::BadSignature);
+                }
+                // 已经过期的直接丢掉。有效期太远的也不收，因为要在池中等很久
+                if !signed.is_live_at(next_height) {
+                    return Err(MempoolError::Expired);
+                }
+                // 交易靠nonce和输入防重放，upinfo只能查最近的块里有没有同一条
+                if chain
+                    .recent_entries(chain.last_block())
+                    .contains(&signed.replay_id())
+                {
+                    return Err(MempoolError::Included);
+                }
+                // 手续费要现在就付得起，UTXO模型的链上不能带手续费
+                match chain.ledger() {
+                    Ledger::Account(ledger) => {
+                        let balance = ledger.account(&n.public_key).balance;
+                        if balance < n.fee {
+                            return Err(MempoolError::Invalid(
+                                TransactionError::InsufficientBalance {
+                                    balance,
+                                    needed: n.fee,
+                                },
+                            ));
+                        }
+                    }
+                    Ledger::Utxo(_) if n.fee > 0 => {
+                        return Err(MempoolError::Invalid(TransactionError::WrongModel))
+                    }
+                    Ledger::Utxo(_) => {}
+                }
+                n.fee
+            }
+            PoolEntry::Transaction(n) => {
+                if !n.verify(&chain_id) {
+                    return Err(MempoolError::BadSignature);
+                }
+                match chain.ledger() {
+                    Ledger::Account(ledger) => {
+                        let expected = ledger.account(&n.from).nonce;
+                        if n.nonce < expected {
+                            return Err(MempoolError::Invalid(TransactionError::BadNonce {
+                                expected,
+                                got: n.nonce,
+                            }));
+                        }
+                    }
+                    Ledger::Utxo(_) => {
+                        return Err(MempoolError::Invalid(TransactionError::WrongModel))
+                    }
+                }
+                n.fee
+            }
+            PoolEntry::UtxoTransaction(n) => match chain.ledger() {
+                // 输入可以是池中另一笔交易的输出，但必须找得到，签名也要验过。找不到输入的交易不收
+                Ledger::Utxo(ledger) => ledger
+                    .check_transaction_with(n, |outpoint| {
+                        ledger
+                            .output(outpoint)
+                            .or_else(|| self.pool_output(outpoint))
+                    })
+                    .map_err(MempoolError::Invalid)?,
+                Ledger::Account(_) => {
+                    return Err(MempoolError::Invalid(TransactionError::WrongModel))
+                }
+            },
+        };
+        self.insert(entry, fee, next_height - 1)
+    }
+
+    // 池中某笔UTXO交易的输出
+    fn pool_output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        match &self.entries.get(&outpoint.txid)?.entry {
+            PoolEntry::UtxoTransaction(tx) => tx.outputs.get(outpoint.index as usize).cloned(),
+            _ => None,
+        }
+    }
+
+    // 不做任何检查直接放进池中。池满时挤掉每字节手续费最低的条目，腾不出地方就拒绝
+    pub fn insert(
+        &mut self,
+        entry: PoolEntry,
+        fee: u64,
+        height: usize,
+    ) -> Result<Hash, MempoolError> {
+        let id = entry.id();
+        if self.entries.contains_key(&id) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        let pending = Pending {
+            size: entry.encoded_len(),
+            entry,
+            fee,
+            added_at: height,
+            seq: self.next_seq,
+        };
+        if pending.size > MAX_BLOCK_BODY_BYTES.min(self.max_bytes) {
+            return Err(MempoolError::TooLarge);
+        }
+
+        let mut evicted = vec![];
+        let mut bytes = self.bytes;
+        while self.entries.len() - evicted.len() >= self.max_entries
+            || bytes + pending.size > self.max_bytes
+        {
+            let worst = self
+                .entries
+                .iter()
+                .filter(|(id, _)| !evicted.contains(*id))
+                .min_by(|(_, a), (_, b)| {
+                    if a.pays_more_than(b) {
+                        std::cmp::Ordering::Greater
+                    } else if b.pays_more_than(a) {
+                        std::cmp::Ordering::Less
+                    } else {
+                        // 手续费一样时先挤掉后来的
+                        b.seq.cmp(&a.seq)
+                    }
+                });
+            match worst {
+                Some((worst_id, worst)) if pending.pays_more_than(worst) => {
+                    bytes -= worst.size;
+                    evicted.push(*worst_id);
+                }
+                _ => return Err(MempoolError::FeeTooLow),
+            }
+        }
+        for id in evicted {
+            self.remove(&id);
+        }
+
+        self.next_seq += 1;
+        self.bytes += pending.size;
+        self.entries.insert(id, pending);
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: &Hash) -> Option<PoolEntry> {
+        let pending = self.entries.remove(id)?;
+        self.bytes -= pending.size;
+        Some(pending.entry)
+    }
+
+    // 块被接进主链之后，把块中的条目从池中删掉
+    pub fn remove_included(&mut self, block: &Block) {
+        for id in block_entry_ids(block) {
+            self.remove(&id);
+        }
+    }
+
+    // 主链末尾到了tip_height时，删掉进池超过expiry_blocks个块的条目，以及下一个块已经不能再打包的upinfo。返回删掉的条数
+    pub fn expire(&mut self, tip_height: usize) -> usize {
+        let before = self.entries.len();
+        let expiry_blocks = self.expiry_blocks;
+        let expired: Vec<Hash> = self
+            .entries
+            .iter()
+            .filter(|(_, n)| {
+                n.added_at + expiry_blocks <= tip_height
+                    || matches!(&n.entry, PoolEntry::Upinfo(u) if u.expires_at <= tip_height)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(&id);
+        }
+        before - self.entries.len()
+    }
+
+    // 按进池的顺序把条目写成一个json数组。手续费和进池高度不保存，加载时重新算。
+    // 先写临时文件再改名，写到一半崩溃也不会把上一次保存的文件弄坏
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut pending: Vec<&Pending> = self.entries.values().collect();
+        pending.sort_by_key(|n| n.seq);
+        let entries: Vec<&PoolEntry> = pending.into_iter().map(|n| &n.entry).collect();
+        let tmp = path.as_ref().with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(entries.len())
+    }
+
+    // 加载save保存的条目，在chain的主链末尾重新验证之后放进池中，已经上链、过期或者不再合法的条目丢掉。
+    // 文件不存在时什么都不做。返回放进池中的条数和丢掉的条数
+    pub fn load(&mut self, path: impl AsRef<Path>, chain: &Chain) -> io::Result<(usize, usize)> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+            Err(e) => return Err(e),
+        };
+        let entries: Vec<PoolEntry> = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let (mut admitted, mut dropped) = (0, 0);
+        for entry in entries {
+            if self.admit(entry, chain).is_ok() {
+                admitted += 1;
+            } else {
+                dropped += 1;
+            }
+        }
+        Ok((admitted, dropped))
+    }
+
+    // 按每字节手续费从高到低贪心地挑出一个块的条目，总条数不超过max_entries，总字节数不超过max_bytes。
+    // check决定每个候选条目能不能打包；被Skip的条目在后面的条目打包之后会再试一次，
+    // 这样同一个账户nonce靠后但手续费高的交易也能跟在前面的交易之后打包。被Reject的条目从池中删掉
+    pub fn build_template(
+        &mut self,
+        max_entries: usize,
+        max_bytes: usize,
+        mut check: impl FnMut(&PoolEntry) -> Verdict,
+    ) -> Vec<PoolEntry> {
+        let mut candidates: Vec<(&Hash, &Pending)> = self.entries.iter().collect();
+        candidates.sort_by(|(_, a), (_, b)| {
+            if a.pays_more_than(b) {
+                std::cmp::Ordering::Less
+            } else if b.pays_more_than(a) {
+                std::cmp::Ordering::Greater
+            } else {
+                a.seq.cmp(&b.seq)
+            }
+        });
+
+        let mut included = vec![];
+        let mut rejected = vec![];
+        let mut bytes = 0;
+        loop {
+            let mut progress = false;
+            let mut skipped = vec![];
+            for (id, pending) in candidates {
+                if included.len() >= max_entries || bytes + pending.size > max_bytes {
+                    skipped.push((id, pending));
+                    continue;
+                }
+                match check(&pending.entry) {
+                    Verdict::Include => {
+                        bytes += pending.size;
+                        included.push(pending.entry.clone());
+                        progress = true;
+                    }
+                    Verdict::Skip => skipped.push((id, pending)),
+                    Verdict::Reject => rejected.push(*id),
+                }
+            }
+            candidates = skipped;
+            if !progress || candidates.is_empty() || included.len() >= max_entries {
+                break;
+            }
+        }
+
+        for id in rejected {
+            self.remove(&id);
+        }
+        included
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::*;
+    use crate::difficulty;
+    use crate::ledger::AccountLedger;
+    use crate::utxo::UtxoLedger;
+
+    // 只有创世块的链，只在内存中
+    fn genesis_chain() -> Chain {
+        regtest_chain(&regtest_spec()).0
+    }
+
+    // keypair(from)转给keypair(9)一个币
+    fn transfer(chain: &Chain, from: u8, nonce: u64, fee: u64) -> PoolEntry {
+        let to = keypair(9).public.to_bytes().to_vec();
+        let tx = Transaction::new_signed(to, 1, fee, nonce, &chain.chain_id(), &keypair(from));
+        PoolEntry::Transaction(tx)
+    }
+
+    fn upinfo(chain: &Chain, text: &str, expires_at: usize) -> PoolEntry {
+        paid_upinfo(chain, text, expires_at, 0)
+    }
+
+    fn paid_upinfo(chain: &Chain, text: &str, expires_at: usize, fee: u64) -> PoolEntry {
+        let text = text.to_string();
+        let entry = SignedEntry::new_signed(text, expires_at, fee, &chain.chain_id(), &keypair(1));
+        PoolEntry::Upinfo(entry.into())
+    }
+
+    fn fee_of(entry: &PoolEntry) -> u64 {
+        match entry {
+            PoolEntry::Transaction(n) => n.fee,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn admit_dedups_and_checks_entries() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        let entry = transfer(&chain, 1, 0, 1);
+        let id = pool.admit(entry.clone(), &chain).unwrap();
+        assert_eq!(id, entry.id());
+        assert_eq!(pool.admit(entry, &chain), Err(MempoolError::AlreadyKnown));
+
+        let mut forged = transfer(&chain, 1, 1, 1);
+        if let PoolEntry::Transaction(tx) = &mut forged {
+            tx.amount += 1;
+        }
+        assert_eq!(pool.admit(forged, &chain), Err(MempoolError::BadSignature));
+
+        // 链上已经过期的upinfo不收
+        let height = chain.last_block().height;
+        let stale = upinfo(&chain, "stale", height);
+        assert_eq!(pool.admit(stale, &chain), Err(MempoolError::Expired));
+        pool.admit(upinfo(&chain, "live", height + 10), &chain)
+            .unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn upinfo_fee_is_signed_and_must_be_affordable() {
+        // keypair(1)挖了一个块，才付得起手续费
+        let (chain, _) = funded::<AccountLedger>();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        let expires_at = chain.last_block().height + 10;
+        let reward = chain.block_reward(1);
+
+        let mut raised = paid_upinfo(&chain, "raised", expires_at, 1);
+        if let PoolEntry::Upinfo(n) = &mut raised {
+            n.fee = 1000;
+        }
+        assert_eq!(pool.admit(raised, &chain), Err(MempoolError::BadSignature));
+
+        let too_dear = paid_upinfo(&chain, "too dear", expires_at, reward + 1);
+        assert_eq!(
+            pool.admit(too_dear, &chain),
+            Err(MempoolError::Invalid(
+                TransactionError::InsufficientBalance {
+                    balance: reward,
+                    needed: reward + 1
+                }
+            ))
+        );
+
+        // 带手续费的upinfo排在不带的前面
+        pool.admit(paid_upinfo(&chain, "free", expires_at, 0), &chain)
+            .unwrap();
+        pool.admit(paid_upinfo(&chain, "paid", expires_at, 5), &chain)
+            .unwrap();
+        let picked = pool.build_template(1, usize::MAX, |_| Verdict::Include);
+        assert!(matches!(&picked[..], [PoolEntry::Upinfo(n)] if n.upinfo == "paid"));
+    }
+
+    #[test]
+    fn utxo_inputs_must_resolve_against_chain_or_pool() {
+        use crate::utxo::{OutPoint, TxOut, UtxoTransaction};
+        let (chain, ledger) = funded::<UtxoLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let pay = |to: &ed25519_dalek::Keypair, amount| TxOut {
+            public_key: to.public.to_bytes().to_vec(),
+            amount,
+        };
+        let (coin, output) = ledger.outputs_of(alice.public.as_bytes())[0].clone();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+
+        // 引用的输出在链上和池中都找不到，算不出手续费，不收
+        let phantom = OutPoint {
+            txid: [7; 32],
+            index: 0,
+        };
+        let orphan = UtxoTransaction::new_signed(
+            vec![phantom],
+            vec![pay(&bob, 1)],
+            &chain.chain_id(),
+            &alice,
+        );
+        assert_eq!(
+            pool.admit(PoolEntry::UtxoTransaction(orphan), &chain),
+            Err(MempoolError::Invalid(TransactionError::UnknownInput {
+                input: 0
+            }))
+        );
+
+        // 花池中另一笔交易的输出可以，手续费也算得出来
+        let parent = UtxoTransaction::new_signed(
+            vec![coin],
+            vec![pay(&bob, output.amount - 1)],
+            &chain.chain_id(),
+            &alice,
+        );
+        let bob_coin = OutPoint {
+            txid: parent.txid(),
+            index: 0,
+        };
+        pool.admit(PoolEntry::UtxoTransaction(parent), &chain)
+            .unwrap();
+        let child = UtxoTransaction::new_signed(
+            vec![bob_coin],
+            vec![pay(&alice, output.amount - 4)],
+            &chain.chain_id(),
+            &bob,
+        );
+        let forged = UtxoTransaction::new_signed(
+            vec![bob_coin],
+            vec![pay(&alice, 1)],
+            &chain.chain_id(),
+            &alice,
+        );
+        assert_eq!(
+            pool.admit(PoolEntry::UtxoTransaction(forged), &chain),
+            Err(MempoolError::Invalid(TransactionError::BadSignature))
+        );
+        pool.admit(PoolEntry::UtxoTransaction(child), &chain)
+            .unwrap();
+        assert_eq!(pool.len(), 2);
+        let picked = pool.build_template(1, usize::MAX, |_| Verdict::Include);
+        // 子交易每字节手续费更高
+        assert!(
+            matches!(&picked[..], [PoolEntry::UtxoTransaction(n)] if n.inputs[0].previous_output == bob_coin)
+        );
+    }
+
+    #[test]
+    fn full_pool_evicts_the_lowest_fee_rate() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(2, 1 << 20, 10);
+        pool.insert(transfer(&chain, 1, 0, 1), 1, 1).unwrap();
+        pool.insert(transfer(&chain, 2, 0, 3), 3, 1).unwrap();
+        pool.insert(transfer(&chain, 3, 0, 2), 2, 1).unwrap();
+        assert_eq!(pool.len(), 2);
+
+        // 不比池中最低的高就进不来
+        assert_eq!(
+            pool.insert(transfer(&chain, 4, 0, 2), 2, 1),
+            Err(MempoolError::FeeTooLow)
+        );
+        let picked = pool.build_template(10, usize::MAX, |_| Verdict::Include);
+        let fees: Vec<u64> = picked.iter().map(fee_of).collect();
+        assert_eq!(fees, vec![3, 2]);
+    }
+
+    #[test]
+    fn byte_limit_is_enforced() {
+        let chain = genesis_chain();
+        let size = transfer(&chain, 1, 0, 1).encoded_len();
+        let mut pool = Mempool::new(10, size * 2, 10);
+        pool.insert(transfer(&chain, 1, 0, 1), 1, 1).unwrap();
+        pool.insert(transfer(&chain, 2, 0, 2), 2, 1).unwrap();
+        pool.insert(transfer(&chain, 3, 0, 3), 3, 1).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.bytes(), size * 2);
+
+        let mut tiny = Mempool::new(10, size - 1, 10);
+        assert_eq!(
+            tiny.insert(transfer(&chain, 1, 0, 1), 1, 1),
+            Err(MempoolError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn entries_expire_after_n_blocks() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(10, 1 << 20, 5);
+        pool.insert(transfer(&chain, 1, 0, 1), 1, 1).unwrap();
+        pool.insert(upinfo(&chain, "soon", 3), 0, 1).unwrap();
+        assert_eq!(pool.expire(2), 0);
+        // 到了过期高度，下一个块已经不能再打包这条upinfo
+        assert_eq!(pool.expire(3), 1);
+        assert_eq!(pool.expire(5), 0);
+        assert_eq!(pool.expire(6), 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.bytes(), 0);
+    }
+
+    #[test]
+    fn included_entries_are_removed() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        let included = transfer(&chain, 1, 0, 1);
+        let left = upinfo(&chain, "left", chain.last_block().height + 10);
+        pool.admit(included.clone(), &chain).unwrap();
+        pool.admit(left.clone(), &chain).unwrap();
+
+        // 比如别的节点挖出来的块，这里只看块体
+        let mut block = chain.last_block().clone();
+        if let PoolEntry::Transaction(tx) = included {
+            block.transactions.push(tx);
+        }
+        pool.remove_included(&block);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.bytes(), left.encoded_len());
+        let picked = pool.build_template(10, usize::MAX, |_| Verdict::Include);
+        assert_eq!(picked[0].id(), left.id());
+    }
+
+    #[test]
+    fn upinfo_already_on_chain_is_not_admitted() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let expires_at = chain.last_block().height + 10;
+        let entry = upinfo(&chain, "on chain", expires_at);
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &keypair(2));
+        if let PoolEntry::Upinfo(n) = &entry {
+            block.upinfo.push(n.clone().into());
+        }
+        block.merkle_root = block.compute_merkle_root();
+        while !difficulty::hash_meets_target(&block.hash(), block.bits) {
+            block.nonce += 1;
+        }
+        chain.try_add_a_block(block).unwrap();
+
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        assert_eq!(pool.admit(entry, &chain), Err(MempoolError::Included));
+        // 内容一样、有效期不同的是另一条upinfo
+        let again = upinfo(&chain, "on chain", expires_at + 1);
+        assert!(pool.admit(again, &chain).is_ok());
+    }
+
+    #[test]
+    fn template_takes_the_best_fee_rate_first() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        for (seed, fee) in [(1, 1), (2, 5), (3, 3), (4, 4)] {
+            pool.insert(transfer(&chain, seed, 0, fee), fee, 1).unwrap();
+        }
+        let picked = pool.build_template(3, usize::MAX, |_| Verdict::Include);
+        let fees: Vec<u64> = picked.iter().map(fee_of).collect();
+        assert_eq!(fees, vec![5, 4, 3]);
+        // 打包不会把条目从池中拿走
+        assert_eq!(pool.len(), 4);
+    }
+
+    #[test]
+    fn template_retries_skipped_entries_and_drops_rejected_ones() {
+        let chain = genesis_chain();
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        // nonce为1的手续费更高，排在前面，但要等nonce为0的打包之后才能打包
+        pool.insert(transfer(&chain, 1, 1, 5), 5, 1).unwrap();
+        pool.insert(transfer(&chain, 1, 0, 1), 1, 1).unwrap();
+        let rejected = pool.insert(transfer(&chain, 2, 0, 3), 3, 1).unwrap();
+
+        let mut next_nonce = 0;
+        let picked = pool.build_template(10, usize::MAX, |entry| match entry {
+            PoolEntry::Transaction(tx) if tx.from == keypair(2).public.to_bytes() => {
+                Verdict::Reject
+            }
+            PoolEntry::Transaction(tx) if tx.nonce == next_nonce => {
+                next_nonce += 1;
+                Verdict::Include
+            }
+            _ => Verdict::Skip,
+        });
+        let fees: Vec<u64> = picked.iter().map(fee_of).collect();
+        assert_eq!(fees, vec![1, 5]);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.remove(&rejected).is_none());
+    }
+
+    #[test]
+    fn saved_pool_loads_back() {
+        let chain = genesis_chain();
+        let dir = std::env::temp_dir().join(format!("runchain-mempool-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mempool.json");
+        let mut pool = Mempool::new(10, 1 << 20, 10);
+        pool.admit(transfer(&chain, 1, 0, 1), &chain).unwrap();
+        pool.admit(
+            upinfo(&chain, "kept", chain.last_block().height + 10),
+            &chain,
+        )
+        .unwrap();
+        assert_eq!(pool.save(&path).unwrap(), 2);
+
+        let mut loaded = Mempool::new(10, 1 << 20, 10);
+        assert_eq!(loaded.load(&path, &chain).unwrap(), (2, 0));
+        assert_eq!(loaded.bytes(), pool.bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
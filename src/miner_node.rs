@@ -2,29 +2,173 @@ use chrono::Utc;
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
+mod archive;
 mod block;
+mod cli;
+mod config;
+mod clock;
 mod cryptography;
+mod difficulty;
+mod genesis;
+mod keystore;
+mod ledger;
+mod mempool;
 mod p2p;
 mod pow;
 mod protocol;
+mod storage;
+mod template;
+mod transaction;
+mod utxo;
 
-use crate::block::Block;
+use crate::block::{AddBlockOutcome, Block, BlockValidationError, Hash};
+use crate::mempool::{Mempool, MempoolError, PoolEntry};
 use p2p::*;
 use protocol::*;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
-static FLAG: AtomicBool = AtomicBool::new(true);
+// 向partner_peer_id请求它主链上最近的num_of_blocks个块
+fn request_blocks(
+    swarm: &mut Swarm<RunChainBehaviour>,
+    partner_peer_id: &str,
+    num_of_blocks: usize,
+) {
+    let request_blocks = RequestNewBlocks {
+        event_mod: EventMod::ONE((p2p::PEER_ID.to_string(), partner_peer_id.to_string())),
+        num_of_blocks, // 请求的块的个数
+    };
+    let request_blocks = MessageEvent::RequestNewBlocks(request_blocks);
+    let json = serde_json::to_string(&request_blocks).expect("can jsonify response");
+    swarm
+        .behaviour_mut()
+        .floodsub
+        .publish(TOPIC.clone(), json.as_bytes());
+}
+
+// 广播本节点交易池中新进来的条目的id
+fn announce_inventory(swarm: &mut Swarm<RunChainBehaviour>, ids: Vec<Hash>) {
+    let inventory = MessageEvent::InventoryAnnounce(InventoryAnnounce {
+        peer_id: p2p::PEER_ID.to_string(),
+        ids,
+    });
+    let json = serde_json::to_string(&inventory).expect("can jsonify inventory");
+    swarm
+        .behaviour_mut()
+        .floodsub
+        .publish(TOPIC.clone(), json.as_bytes());
+}
+
+// 向partner_peer_id请求它交易池中的这些条目
+fn request_entries(swarm: &mut Swarm<RunChainBehaviour>, partner_peer_id: &str, ids: Vec<Hash>) {
+    let request_entries = MessageEvent::RequestEntries(RequestEntries {
+        event_mod: EventMod::ONE((p2p::PEER_ID.to_string(), partner_peer_id.to_string())),
+        ids,
+    });
+    let json = serde_json::to_string(&request_entries).expect("can jsonify request");
+    swarm
+        .behaviour_mut()
+        .floodsub
+        .publish(TOPIC.clone(), json.as_bytes());
+}
+
+// 对方发来非法块时给它记的惩罚分，累计到BAN_SCORE_THRESHOLD就不再从它同步
+fn penalty_for(e: &BlockValidationError) -> u32 {
+    match e {
+        BlockValidationError::BadProofOfWork => BAN_SCORE_THRESHOLD,
+        // 重复的块、缺父块、写盘失败都不是对方的错；时间戳太超前可能只是双方的时钟不一致
+        BlockValidationError::AlreadyKnown
+        | BlockValidationError::UnknownParent
+        | BlockValidationError::Storage(_)
+        | BlockValidationError::TimestampTooFarInFuture { .. } => 0,
+        _ => BAN_SCORE_THRESHOLD / 5,
+    }
+}
+
+// 终端输入的挖矿控制命令
+fn handle_command(engine: &pow::MiningEngine, command: &str) {
+    match command {
+        "pause" => {
+            engine.pause();
+            println!("⏸️挖矿已暂停，输入resume恢复");
+        }
+        "resume" => {
+            if engine.state() == pow::MinerState::Stopped {
+                println!("⛔挖矿已经停止，不能恢复");
+            } else {
+                engine.resume();
+                println!("▶️挖矿已恢复");
+            }
+        }
+        "stop" => {
+            engine.stop();
+            println!("⏹️挖矿已停止，节点继续同步和转发");
+        }
+        "status" => println!(
+            "⛏️挖矿状态:{:?}，{}个线程，算力:{:.0} H/s",
+            engine.state(),
+            engine.threads(),
+            engine.hash_rate()
+        ),
+        "" => {}
+        _ => println!("❓未知命令{}，可用命令:pause | resume | stop | status", command),
+    }
+}
 
-use rand::Rng;
-use rs_merkle::{algorithms::Sha256, Hasher, MerkleTree};
+// 等到进程收到Ctrl-C或者SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
 #[tokio::main]
 async fn main() {
+    // 链数据存放的目录，可以通过环境变量RUNCHAIN_DATA_DIR指定
+    let data_dir = std::env::var("RUNCHAIN_DATA_DIR").unwrap_or_else(|_| "runchain_data".to_string());
+    let genesis_spec = genesis::GenesisSpec::load().expect("can load genesis spec");
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args, &data_dir, &genesis_spec));
+    }
+
     println!("🔗Peer ID:{}", p2p::PEER_ID.clone());
+    // 出块奖励记在这个公钥名下
+    let miner_public_key = keystore::load_or_create(&data_dir)
+        .expect("can load miner key")
+        .public
+        .to_bytes()
+        .to_vec();
+    println!("⛏️矿工公钥:{}", hex::encode(&miner_public_key));
+    let node_config = config::NodeConfig::load(&data_dir).expect("can load node config");
+    println!(
+        "⚙️区块模板:每块最多{}条、{}字节，打包间隔{}~{}毫秒",
+        node_config.template.max_entries,
+        node_config.template.max_bytes,
+        node_config.template.min_interval_ms,
+        node_config.template.max_interval_ms
+    );
+    // 挖矿引擎，代替原来的全局FLAG。同步时暂停，也可以在终端输入命令控制，见handle_command
+    let engine = Arc::new(pow::MiningEngine::new(&node_config.mining));
+    let engine_arc_copy = Arc::clone(&engine);
+    println!(
+        "⚙️挖矿:{}个线程，每个线程最多占用{}%的CPU",
+        engine.threads(),
+        node_config.mining.cpu_percent
+    );
     let (response_sender, mut response_receiver) =
-        mpsc::unbounded_channel::<protocol::MessageEvent>();
+        mpsc::unbounded_channel::<(protocol::MessageEvent, String)>();
 
     let (new_block_sender, mut new_block_receiver) =
         mpsc::unbounded_channel::<(protocol::MessageEvent, String)>();
@@ -73,154 +217,106 @@ async fn main() {
 
     enum EventType {
         IsTimeToSendChainInfo,
-        MessageEvent(protocol::MessageEvent),
+        IsTimeToSaveMempool,
+        Shutdown,
+        IsTimeToReportHashRate,
+        Command(String),
+        WalletEntries(Vec<protocol::MessageEvent>),
+        MessageEvent(protocol::MessageEvent, String), // 消息和发来它的节点
     }
 
-    let runchain = Arc::new(RwLock::new(block::Chain::new()));
+    let runchain = Arc::new(RwLock::new(
+        block::Chain::open(&data_dir, &genesis_spec).expect("can open chain data dir"),
+    ));
+    let my_genesis_hash = runchain.read().unwrap().genesis_hash();
+    // 给发过非法块的节点记的惩罚分
+    let mut peer_penalties: HashMap<String, u32> = HashMap::new();
     let runchain_arc_copy = Arc::clone(&runchain);
     let runchain_arc_copy_copy = Arc::clone(&runchain);
 
-    // 获取准许挖矿的管道
-    let (allow_pow_sender, mut allow_pow_receiver) = tokio::sync::mpsc::channel::<bool>(1);
-
-    let mut new_up_infos = vec![];
-
-    fn judge_if_time_is_up(t: Instant) -> bool {
-        let new_now = std::time::Instant::now();
-        if new_now.saturating_duration_since(t) > std::time::Duration::from_secs(3) {
-            println!("打包时间结束");
-            true
-        } else {
-            false
+    // 交易池，挖矿线程从中挑条目打包，主循环在收到别人的块之后从中删掉已经上链的
+    let mempool = Arc::new(Mutex::new(Mempool::new(
+        MEMPOOL_MAX_ENTRIES,
+        MEMPOOL_MAX_BYTES,
+        MEMPOOL_EXPIRY_BLOCKS,
+    )));
+    let mempool_arc_copy = Arc::clone(&mempool);
+    // 上次退出时保存的交易池，在当前的主链末尾重新验证之后放回池中
+    let mempool_path = Path::new(&data_dir).join("mempool.json");
+    {
+        let chain = runchain.read().unwrap();
+        match mempool.lock().unwrap().load(&mempool_path, &chain) {
+            Ok((0, 0)) => {}
+            Ok((admitted, dropped)) => {
+                println!("📥从磁盘恢复了{}条交易池条目，丢弃了{}条", admitted, dropped)
+            }
+            Err(e) => println!("⚠️无法读取保存的交易池:{}", e),
         }
     }
+    // 交易池进了新条目时main通过它叫醒挖矿线程，看看是不是够一个块了
+    let (pool_changed_sender, mut pool_changed_receiver) = mpsc::unbounded_channel::<()>();
+
+    let template_config = node_config.template;
+    let runtime = tokio::runtime::Handle::current();
 
     tokio::task::spawn_blocking(move || {
         loop {
-            // 在这里组装交易
-            // new_transaction_receiver 就是给它用的,反复收上链请求。截止条件是超过几秒或者是交易池满了
-            let now = std::time::Instant::now();
+            // 暂停时阻塞在这里，停止了就结束挖矿线程
+            if !engine_arc_copy.wait_until_running() {
+                println!("⏹️挖矿线程已退出");
+                return;
+            }
 
+            // 在这里组装交易
+            // 上链请求由main收进交易池，这里只等一阵：交易池中的条目够一个块了就只等到min_interval，否则最多等到max_interval。
+            // 交易池没变化时就阻塞在管道上，main叫醒时再看一眼。上一轮挖矿期间攒下的叫醒先清掉
+            while pool_changed_receiver.try_recv().is_ok() {}
+            let started = Instant::now();
             loop {
-                match new_transaction_receiver.try_recv() {
-                    Ok((MessageEvent::NewUPINFO(new_upinfo), _)) => {
-                        new_up_infos.push(new_upinfo);
-                        if new_up_infos.len() >= 16 || judge_if_time_is_up(now) {
-                            break;
-                        }
-                    }
-                    _ => {
-                        if judge_if_time_is_up(now) {
-                            break;
-                        }
-                    }
+                let elapsed = started.elapsed();
+                let filled = template_config.is_filled_by(&mempool_arc_copy.lock().unwrap());
+                let deadline = if filled {
+                    template_config.min_interval()
+                } else {
+                    template_config.max_interval()
+                };
+                if elapsed >= deadline {
+                    break;
                 }
+                let _ = runtime.block_on(timeout(deadline - elapsed, pool_changed_receiver.recv()));
             }
 
-            println!(
-                "开始验证交易,当前new_up_infos的长度为:{}",
-                new_up_infos.len()
-            );
-
-            // 此时new_up_infos中可能已经存放了一些upinfos
-            // 先挨个做验证，把非法上链信息剔除之后就开始构建默克尔树并计算默克尔根的哈希.
-            // 无论是不是空的都直接构建默克尔树。万一是空的就直接挖空块。
-            // 构建完之后就可以组装块让pow服务去挖了
-
-            // 验证签名,把能成功验证签名的NewUPINFO留下，收集到verified_up_infos中。这个是要备份的。
-            // 因为如果挖矿失败，verified_up_infos中的内容要被重新收回到交易池new_up_infos中
-            let verified_up_infos: Vec<NewUPINFO> = new_up_infos
-                .clone()
-                .into_iter()
-                .filter(|n| {
-                    let NewUPINFO {
-                        upinfo,
-                        signature,
-                        public_key,
-                    } = n;
-                    cryptography::verify(public_key, upinfo, signature)
-                })
-                .collect();
-
-            new_up_infos.clear();
-
-            // 构建默克尔树
-
-            // 得到计算默克尔根所需的vec
-            let merkel_original_vec: Vec<String> = verified_up_infos
-                .clone()
-                .into_iter()
-                .map(|n| n.upinfo)
-                .collect();
-
-            let leaves: Vec<[u8; 32]> = merkel_original_vec
-                .iter()
-                .map(|x| Sha256::hash(x.as_bytes()))
-                .collect();
-
-            let merkle_tree = MerkleTree::<Sha256>::from_leaves(&leaves);
-
-            let merkle_root = match merkle_tree.root() {
-                Some(value) => value,
-                None => {
-                    println!("默克尔根计算失败,因为当前没有交易请求，插入一个默认随机默克尔根");
-                    let mut rng = rand::thread_rng();
-                    [
-                        rng.gen_range(0, 255),
-                        151,129,18,202,27,189,202,250,194,49,179,154,35,220,77,167,134,239,248,20,124,78,114,185,128,119,133,175,238,72,187,   
-                    ]
-                }
-            };
-
-            let blocks = runchain_arc_copy.read().unwrap();
-            let main_chain_last_block = blocks.last_block();
-
-            let height = (main_chain_last_block.height + 1) as usize;
-
-            let previous_hash = runchain_arc_copy // 这地方可能会死锁。。？
-                .read()
-                .unwrap()
-                .calculate_hash(main_chain_last_block)
-                .unwrap();
-            drop(blocks);
-            let timestamp = format!("{}", Utc::now());
-
             // 打包好块，送去挖矿
-            let block = Block {
-                height,
-                previous_hash: previous_hash.clone(),
-                timestamp: timestamp.clone(),
-                merkle_root: merkle_root.clone(),
-                nonce: 0,
-                upinfo: vec![],
+            let block = {
+                let blocks = runchain_arc_copy.read().unwrap();
+                let mut mempool = mempool_arc_copy.lock().unwrap();
+                template::assemble(
+                    &blocks,
+                    &mut mempool,
+                    &template_config,
+                    &miner_public_key,
+                    Utc::now().timestamp_millis(),
+                )
             };
 
-            let (nonce, flag) = pow::pow_v2(block, &FLAG);
+            let nonce = engine_arc_copy.mine(block.header());
 
-            if !flag {
-                println!("将交易放回内存池");
-                // 把upinfos放回交易池new_up_infos，
-                new_up_infos = verified_up_infos;
-                allow_pow_receiver.blocking_recv();
-            } else {
+            if let Some(nonce) = nonce {
                 // 走到这个分支说明挖出了新块
 
                 println!("挖出了新块");
 
                 // 将block添加到主链上
-                let block = Block {
-                    height,
-                    previous_hash,
-                    timestamp,
-                    merkle_root,
-                    nonce,
-                    upinfo: merkel_original_vec,
-                };
-                runchain_arc_copy
-                    .write()
-                    .unwrap()
-                    .try_add_a_block(block)
-                    .unwrap();
+                let block = Block { nonce, ..block };
+                let result = runchain_arc_copy.write().unwrap().try_add_a_block(block.clone());
+                if let Err(e) = result {
+                    println!("⛔挖出的新块没能上链:{}", e);
+                    continue;
+                }
+                let mut mempool = mempool_arc_copy.lock().unwrap();
+                mempool.remove_included(&block);
+                mempool.expire(block.height);
+                drop(mempool);
                 println!("添加块成功，向外广播。并打印当前链:");
                 let runchain_lock = runchain_arc_copy.read().unwrap();
                 runchain_lock.show_chain();
@@ -230,35 +326,70 @@ async fn main() {
     });
 
     let get_newest_chaininfo = || {
-        let last_block = runchain_arc_copy_copy.read().unwrap();
-        let last_block = last_block.last_block();
-
+        // 只拿一次读锁。拿着读锁再去拿读锁的话，中间有线程在等写锁就会死锁
+        let chain = runchain_arc_copy_copy.read().unwrap();
         let peer_id = PEER_ID.clone().to_string();
-        let genesis_hash = runchain_arc_copy_copy.read().unwrap().genesis_hash();
-        let block_height = last_block.height;
-        drop(last_block);
+        let genesis_hash = chain.genesis_hash();
+        let total_work = chain.tip_work();
+        let block_height = chain.last_block().height;
+        drop(chain);
         let chain_info = ChainInfo {
             peer_id,
             topic: TOPICSTRING.clone(),
             genesis_hash,
             block_height,
+            total_work,
         };
         chain_info
     };
 
+    // 定时保存交易池，收到退出信号时也保存一次再退出
+    let mut save_mempool_timer =
+        tokio::time::interval(Duration::from_secs(MEMPOOL_SAVE_INTERVAL_SECS));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let report_interval = Duration::from_secs(MINING_REPORT_INTERVAL_SECS);
+    let mut report_hash_rate_timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + report_interval, report_interval);
+    // 从终端读控制挖矿的命令。stdin被关掉(比如后台运行)之后就不再读了
+    let mut stdin_lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+
     loop {
         let evt = {
             tokio::select! {
+                _ = save_mempool_timer.tick() => Some(EventType::IsTimeToSaveMempool),
+                _ = &mut shutdown => Some(EventType::Shutdown),
+                _ = report_hash_rate_timer.tick() => Some(EventType::IsTimeToReportHashRate),
+                line = stdin_lines.next_line(), if stdin_open => match line {
+                    Ok(Some(line)) => Some(EventType::Command(line)),
+                    _ => {
+                        stdin_open = false;
+                        None
+                    }
+                },
+
                 // 把这个改成timer，正常2s向外传播一次块的信息
                 _ = tokio::time::sleep(tokio::time::Duration::from_secs(2))=>
                     {
                         Some(EventType::IsTimeToSendChainInfo)
                     }
 
-                response = response_receiver.recv() =>
+                // 钱包发来的上链请求，挖矿被暂停或停止时也照常收进交易池
+                Some((message, _)) = new_transaction_receiver.recv() =>
                     {
+                        // 攒在管道里的请求一起处理
+                        let mut messages = vec![message];
+                        while let Ok((message, _)) = new_transaction_receiver.try_recv() {
+                            messages.push(message);
+                        }
+                        Some(EventType::WalletEntries(messages))
+                    }
 
-                        Some(EventType::MessageEvent(response.expect("can not get MessageEvent")))
+                response = response_receiver.recv() =>
+                    {
+                        let (message_event, source) = response.expect("can not get MessageEvent");
+                        Some(EventType::MessageEvent(message_event, source))
                     }
                 _ = swarm.select_next_some() => {
                     // 调用发块ChainInfo的代码
@@ -295,86 +426,164 @@ async fn main() {
                         sended = true;
                     }
                 }
-                EventType::MessageEvent(message_event) => match message_event {
+                EventType::IsTimeToSaveMempool => {
+                    if let Err(e) = mempool.lock().unwrap().save(&mempool_path) {
+                        println!("⚠️保存交易池失败:{}", e);
+                    }
+                }
+                EventType::Shutdown => {
+                    match mempool.lock().unwrap().save(&mempool_path) {
+                        Ok(n) => println!("💾已保存交易池中的{}条条目，退出", n),
+                        Err(e) => println!("⛔保存交易池失败:{}", e),
+                    }
+                    std::process::exit(0);
+                }
+                EventType::IsTimeToReportHashRate => {
+                    if engine.state() == pow::MinerState::Running {
+                        println!("⛏️算力:{:.0} H/s", engine.hash_rate());
+                    }
+                }
+                EventType::Command(line) => handle_command(&engine, line.trim()),
+                EventType::WalletEntries(messages) => {
+                    let chain = runchain.read().unwrap();
+                    let mut mempool = mempool.lock().unwrap();
+                    let mut admitted = vec![];
+                    for entry in messages.into_iter().filter_map(PoolEntry::from_message) {
+                        match mempool.admit(entry, &chain) {
+                            Ok(id) => admitted.push(id),
+                            Err(e) => println!("⛔上链请求没能进入交易池:{}", e),
+                        }
+                    }
+                    drop(mempool);
+                    drop(chain);
+                    if !admitted.is_empty() {
+                        // 挖矿线程停止之后管道就关了，叫不醒也没关系
+                        let _ = pool_changed_sender.send(());
+                        println!("📢向外公告{}条新进交易池的条目", admitted.len());
+                        announce_inventory(&mut swarm, admitted);
+                    }
+                }
+                EventType::MessageEvent(message_event, source) => match message_event {
                     MessageEvent::ChainInfo(chaininfo) => {
                         println!("🍏🍏处理chaininfo");
-                        let partner_peer_id = chaininfo.peer_id.to_string();
-                        let my_pper_id = p2p::PEER_ID.to_string();
+                        // 惩罚分记在消息来源名下，所以屏蔽和同步也都按消息来源来，chaininfo中的peer_id是对方自己填的，不能信
+                        let partner_peer_id = source;
 
                         println!("{} {}", chaininfo.topic, TOPICSTRING.to_string());
 
-                        if chaininfo.topic == TOPICSTRING.to_string() {
-                            println!("收到了同一个区块链网络中其他节点的chain_info,开始判断对方链是否比我方链长");
-                            let t = runchain.read().unwrap();
-                            let block_height = t.block_height();
-                            if chaininfo.block_height > block_height {
-                                println!("对方链比我方链长");
-                                FLAG.store(false, Ordering::Relaxed); // 立即停止计算线程
+                        let penalty = peer_penalties.get(&partner_peer_id).copied().unwrap_or(0);
+                        if penalty >= BAN_SCORE_THRESHOLD {
+                            println!("⛔节点{}发过非法块，已被屏蔽", partner_peer_id);
+                        } else if chaininfo.genesis_hash != my_genesis_hash {
+                            println!(
+                                "⛔节点{}的创世块和我方不一致，不从它同步",
+                                partner_peer_id
+                            );
+                        } else if chaininfo.topic == TOPICSTRING.to_string() {
+                            println!("收到了同一个区块链网络中其他节点的chain_info,开始判断对方链的累计工作量是否比我方链大");
+                            let (block_height, tip_work) = {
+                                let t = runchain.read().unwrap();
+                                (t.last_block().height, t.tip_work())
+                            };
+                            if chaininfo.total_work > tip_work {
+                                println!("对方链的累计工作量比我方链大");
+                                // 立即停止计算线程，记下之前是不是在挖，同步完再决定要不要恢复
+                                let was_mining = engine.pause();
 
                                 println!("🌱🌱🌱立即停止挖矿，开始合并其他节点的块");
 
-                                let difference = chaininfo.block_height - block_height;
+                                // 对方的链可能在最近几个块上和我方分叉了，多请求REORG_LOOKBACK个块，
+                                // 好让分叉点之后的块都能拿到，我方已有的块会被跳过
+                                let difference = (chaininfo.block_height.saturating_sub(block_height)
+                                    + REORG_LOOKBACK)
+                                    .min(chaininfo.block_height);
                                 println!("🌱🌱🌱 difference:{difference}  chaininfo.block_height:{}", chaininfo.block_height);
                                 // 向外发送块请求
-                                let request_blocks = RequestNewBlocks {
-                                    event_mod: EventMod::ONE((
-                                        my_pper_id.clone(),
-                                        partner_peer_id.clone(),
-                                    )),
-                                    num_of_blocks: difference, // 请求的块的个数
-                                };
-                                let request_blocks = MessageEvent::RequestNewBlocks(request_blocks);
-                                let json = serde_json::to_string(&request_blocks)
-                                    .expect("can jsonify response");
-                                swarm
-                                    .behaviour_mut()
-                                    .floodsub
-                                    .publish(TOPIC.clone(), json.as_bytes());
+                                let mut num_of_blocks = difference;
+                                request_blocks(&mut swarm, &partner_peer_id, num_of_blocks);
                                 println!(
                                     "📡由于{}链较长，已经向其请求了块，等待回应中",
-                                    chaininfo.peer_id
+                                    partner_peer_id
                                 );
                                 loop {
                                     println!("开始等待");
-                                    let res =
-                                        timeout(Duration::from_secs(3), new_block_receiver.recv());
-                                    let (flag, new_block) = match res.await {
-                                        Err(_) => (false, MessageEvent::FOO),
-                                        Ok(res) => {
-                                            let (messageevent, _) = res.unwrap();
-                                            (true, messageevent)
+                                    let res = timeout(Duration::from_secs(3), new_block_receiver.recv());
+                                    let (new_block, responder) = match res.await {
+                                        Err(_) => {
+                                            println!("没等到");
+                                            break;
                                         }
+                                        Ok(res) => res.unwrap(),
                                     };
 
-                                    if !flag {
-                                        println!("没等到");
-                                        break;
-                                    }
+                                    // 解析别人对我发来的块回应
+                                    if let MessageEvent::ResponseBlock(resp_block) = new_block {
+                                        let EventMod::ONE((_, my_peer_id)) = resp_block.event_mod;
+                                        if my_peer_id != p2p::PEER_ID.to_string() {
+                                            continue;
+                                        }
+                                        // 只接受我方请求的那个节点发来的块，别的节点(比如被屏蔽的)冒充回应的一律不要
+                                        if responder != partner_peer_id {
+                                            println!("⛔忽略{}发来的块，我方请求的是{}", responder, partner_peer_id);
+                                            continue;
+                                        }
 
-                                    match new_block {
-                                        // 解析别人对我发来的块回应
-                                        MessageEvent::ResponseBlock(resp_block) => {
-                                            let EventMod::ONE((_, my_peer_id)) =
-                                                resp_block.event_mod;
-
-                                            if my_peer_id == p2p::PEER_ID.to_string() {
-                                                // 插入新块
-                                                let new_blocks = resp_block.blocks;
-                                                for block in new_blocks.into_iter() {
-                                                    runchain
-                                                        .write()
-                                                        .unwrap()
-                                                        .try_add_a_block(block)
-                                                        .unwrap()
+                                        // 插入新块
+                                        let mut missing_parent = false;
+                                        for block in resp_block.blocks.into_iter() {
+                                            // 为了覆盖分叉多要的那几个块我方大多已经有了，只用读锁就能跳过
+                                            if runchain.read().unwrap().contains(&block.hash()) {
+                                                continue;
+                                            }
+                                            let result = runchain.write().unwrap().try_add_a_block(block.clone());
+                                            match result {
+                                                Ok(AddBlockOutcome::Extended) => {
+                                                    let mut mempool = mempool.lock().unwrap();
+                                                    mempool.remove_included(&block);
+                                                    mempool.expire(block.height);
+                                                }
+                                                Ok(AddBlockOutcome::Reorganized {
+                                                    disconnected,
+                                                    connected,
+                                                }) => {
+                                                    // 被撤下的块中的upinfo和交易放回交易池重新打包，新主链上已经有了的再删掉。
+                                                    // 放不回去的(比如输入已经被新主链花掉了)就算了
+                                                    let chain = runchain.read().unwrap();
+                                                    let mut mempool = mempool.lock().unwrap();
+                                                    for entry in disconnected.into_iter().flat_map(PoolEntry::all_from_block) {
+                                                        let _ = mempool.admit(entry, &chain);
+                                                    }
+                                                    for block in connected.iter() {
+                                                        mempool.remove_included(block);
+                                                    }
+                                                    mempool.expire(chain.last_block().height);
+                                                }
+                                                Ok(_) | Err(BlockValidationError::AlreadyKnown) => {}
+                                                Err(BlockValidationError::UnknownParent) => missing_parent = true,
+                                                Err(e) => {
+                                                    println!("⛔节点{}发来的块不合法:{}", responder, e);
+                                                    *peer_penalties.entry(responder.clone()).or_insert(0) += penalty_for(&e);
+                                                    // 后面的块都接在这个非法块上，不用再试了
+                                                    break;
                                                 }
-                                                allow_pow_sender.send(true).await.unwrap(); //将状态归位,允许挖矿
-                                                println!("🔥🔥🔥已经拿到新块了!重新开始挖矿");
-                                                break;
                                             }
                                         }
-                                        _ => {}
+
+                                        // 我方没有这批块的父块，说明分叉点比请求的范围还早，扩大范围再请求一次
+                                        if missing_parent && num_of_blocks < chaininfo.block_height {
+                                            num_of_blocks = (num_of_blocks * 2).min(chaininfo.block_height);
+                                            println!("📡缺少祖先块，向{}请求最近的{}个块", responder, num_of_blocks);
+                                            request_blocks(&mut swarm, &partner_peer_id, num_of_blocks);
+                                            continue;
+                                        }
+                                        println!("🔥🔥🔥已经拿到新块了!");
+                                        break;
                                     }
-                                    break;
+                                }
+                                // 不管有没有拿到块都将状态归位。同步前被手动暂停或停止的就保持原样
+                                if was_mining {
+                                    engine.resume();
+                                    println!("🔥🔥🔥重新开始挖矿");
                                 }
                             }
                         }
@@ -394,15 +603,17 @@ async fn main() {
 
                             let numboers_of_block = requestblock.num_of_blocks;
                             let read_to_send_blocks_lock = runchain_arc_copy_copy.read().unwrap();
+                            // 对方要的块可能比我的整条链还多，有多少给多少
                             let read_to_send_blocks =
-                                read_to_send_blocks_lock.last_n_blocks(numboers_of_block);
+                                read_to_send_blocks_lock.last_n_blocks(numboers_of_block).to_vec();
+                            drop(read_to_send_blocks_lock);
 
                             let response_block = ResponseBlock {
                                 event_mod: EventMod::ONE((
                                     p2p::PEER_ID.to_string(),
                                     partner_peer_id,
                                 )),
-                                num_of_blocks: numboers_of_block,
+                                num_of_blocks: read_to_send_blocks.len(),
                                 blocks: read_to_send_blocks,
                             };
                             let response_block = MessageEvent::ResponseBlock(response_block);
@@ -420,6 +631,79 @@ async fn main() {
                         }
                     }
 
+                    // 别的矿工的交易池里有新条目，只请求我方交易池中没有的
+                    MessageEvent::InventoryAnnounce(inventory) => {
+                        if source != p2p::PEER_ID.to_string() {
+                            let mempool = mempool.lock().unwrap();
+                            let unknown: Vec<Hash> = inventory
+                                .ids
+                                .into_iter()
+                                .filter(|n| !mempool.contains(n))
+                                .collect();
+                            drop(mempool);
+                            if !unknown.is_empty() {
+                                request_entries(&mut swarm, &source, unknown);
+                            }
+                        }
+                    }
+
+                    MessageEvent::RequestEntries(request_entries) => {
+                        let EventMod::ONE((partner_peer_id, my_peer_id)) = request_entries.event_mod;
+                        if my_peer_id == p2p::PEER_ID.to_string() {
+                            let mut response_entries = ResponseEntries {
+                                event_mod: EventMod::ONE((p2p::PEER_ID.to_string(), partner_peer_id)),
+                                upinfo: vec![],
+                                transactions: vec![],
+                                utxo_transactions: vec![],
+                            };
+                            // 请求到的时候可能已经上链或者被挤出交易池了，这些就不发
+                            let mempool = mempool.lock().unwrap();
+                            for entry in request_entries.ids.iter().filter_map(|n| mempool.get(n)) {
+                                match entry.clone() {
+                                    PoolEntry::Upinfo(n) => response_entries.upinfo.push(n),
+                                    PoolEntry::Transaction(n) => response_entries.transactions.push(n),
+                                    PoolEntry::UtxoTransaction(n) => response_entries.utxo_transactions.push(n),
+                                }
+                            }
+                            drop(mempool);
+                            let json = serde_json::to_string(&MessageEvent::ResponseEntries(response_entries))
+                                .expect("can jsonify response");
+                            swarm
+                                .behaviour_mut()
+                                .floodsub
+                                .publish(TOPIC.clone(), json.as_bytes());
+                        }
+                    }
+
+                    // 收到的条目和钱包发来的一样要验证之后才能进池，进池成功的再向外公告，这样条目能一跳一跳地传遍全网
+                    MessageEvent::ResponseEntries(response_entries) => {
+                        let EventMod::ONE((partner_peer_id, my_peer_id)) = response_entries.event_mod;
+                        if my_peer_id == p2p::PEER_ID.to_string() {
+                            let entries = response_entries
+                                .upinfo
+                                .into_iter()
+                                .map(PoolEntry::Upinfo)
+                                .chain(response_entries.transactions.into_iter().map(PoolEntry::Transaction))
+                                .chain(response_entries.utxo_transactions.into_iter().map(PoolEntry::UtxoTransaction));
+                            let chain = runchain.read().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let mut admitted = vec![];
+                            for entry in entries {
+                                match mempool.admit(entry, &chain) {
+                                    Ok(id) => admitted.push(id),
+                                    Err(MempoolError::AlreadyKnown | MempoolError::Included) => {}
+                                    Err(e) => println!("⛔节点{}转发的条目没能进入交易池:{}", partner_peer_id, e),
+                                }
+                            }
+                            drop(mempool);
+                            drop(chain);
+                            if !admitted.is_empty() {
+                                let _ = pool_changed_sender.send(());
+                                announce_inventory(&mut swarm, admitted);
+                            }
+                        }
+                    }
+
                     _ => {
                         let chain_info = get_newest_chaininfo();
                         let chain_info = MessageEvent::ChainInfo(chain_info);
@@ -31,8 +31,9 @@ use crate::protocol::{ChainInfo, MessageEvent, ResponseBlock};
 pub struct RunChainBehaviour {
     pub floodsub: Floodsub,
     pub mdns: Mdns,
+    // 和另外两个管道一样带上消息的来源msg.source，main用它而不是消息中自己填的peer_id来判断是谁发的
     #[behaviour(ignore)]
-    pub response_sender_to_main: mpsc::UnboundedSender<MessageEvent>,
+    pub response_sender_to_main: mpsc::UnboundedSender<(MessageEvent, String)>,
     #[behaviour(ignore)]
     pub new_block_sender_to_main: mpsc::UnboundedSender<(MessageEvent, String)>,
     #[behaviour(ignore)]
@@ -46,15 +47,19 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for RunChainBehaviour {
                 match serde_json::from_slice::<MessageEvent>(&msg.data) {
                     Ok(MessageEvent::ChainInfo(chaininfo)) => {
                         println!("💎收到了节点{}的ChainInfo广播", msg.source);
-                        self.report_to_loop_got_info_or_request(MessageEvent::ChainInfo(chaininfo));
+                        self.report_to_loop_got_info_or_request(
+                            MessageEvent::ChainInfo(chaininfo),
+                            msg.source.to_string(),
+                        );
                         return;
                     }
                     
                     Ok(MessageEvent::RequestNewBlocks(requestblock)) => {
                         println!("😆{}节点要求请求新块!", msg.source);
-                        self.report_to_loop_got_info_or_request(MessageEvent::RequestNewBlocks(
-                            requestblock,
-                        ));
+                        self.report_to_loop_got_info_or_request(
+                            MessageEvent::RequestNewBlocks(requestblock),
+                            msg.source.to_string(),
+                        );
                         return;
                     }
 
@@ -78,6 +83,45 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for RunChainBehaviour {
                         return;
                     }
 
+                    Ok(MessageEvent::NewTransaction(transaction)) => {
+                        println!("😆钱包节点{}发来转账交易!", msg.source);
+                        self.report_to_loop_got_new_upinfo(
+                            MessageEvent::NewTransaction(transaction),
+                            msg.source.to_string(),
+                        );
+                    }
+
+                    Ok(MessageEvent::NewUtxoTransaction(transaction)) => {
+                        println!("😆钱包节点{}发来UTXO转账交易!", msg.source);
+                        self.report_to_loop_got_new_upinfo(
+                            MessageEvent::NewUtxoTransaction(transaction),
+                            msg.source.to_string(),
+                        );
+                    }
+
+                    // 交易池之间的同步消息都交给main处理
+                    Ok(MessageEvent::InventoryAnnounce(inventory)) => {
+                        self.report_to_loop_got_info_or_request(
+                            MessageEvent::InventoryAnnounce(inventory),
+                            msg.source.to_string(),
+                        );
+                    }
+
+                    Ok(MessageEvent::RequestEntries(request_entries)) => {
+                        self.report_to_loop_got_info_or_request(
+                            MessageEvent::RequestEntries(request_entries),
+                            msg.source.to_string(),
+                        );
+                    }
+
+                    Ok(MessageEvent::ResponseEntries(response_entries)) => {
+                        println!("😆收到了{}节点发来的交易池条目!", msg.source);
+                        self.report_to_loop_got_info_or_request(
+                            MessageEvent::ResponseEntries(response_entries),
+                            msg.source.to_string(),
+                        );
+                    }
+
                     t => {
                         if t.is_err() {
                             println!("⛔Unexpected message:{:?}", t);
@@ -93,20 +137,22 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for RunChainBehaviour {
 }
 
 impl RunChainBehaviour {
-    fn report_to_loop_got_info_or_request(&self, message_event: MessageEvent) {
-        self.response_sender_to_main.send(message_event).unwrap();
+    // 收消息的一方已经关掉管道(比如节点正在退出)时就丢掉这条消息，不要让swarm跟着panic
+    fn report_to_loop_got_info_or_request(&self, message_event: MessageEvent, source_peer_id: String) {
+        if self.response_sender_to_main.send((message_event, source_peer_id)).is_err() {
+            println!("⚠️main已经不再接收消息，丢弃");
+        }
     }
     fn report_to_loop_got_new_block(&self, new_block: MessageEvent, source_peer_id: String) {
-        self.new_block_sender_to_main
-            .send((new_block, source_peer_id))
-            .unwrap();
-        
+        if self.new_block_sender_to_main.send((new_block, source_peer_id)).is_err() {
+            println!("⚠️main已经不再接收新块，丢弃");
+        }
     }
 
     fn report_to_loop_got_new_upinfo(&self, new_block: MessageEvent, source_peer_id: String) {
-        self.new_transations_sender
-            .send((new_block, source_peer_id))
-            .unwrap();
+        if self.new_transations_sender.send((new_block, source_peer_id)).is_err() {
+            println!("⚠️main已经不再接收上链请求，丢弃");
+        }
     }
 }
 // 这个是mdns提供的事件，可以是节点发现事件，也可以是节点过期事件
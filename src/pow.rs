@@ -1,43 +1,299 @@
+// 挖矿引擎：在自己的rayon线程池里找满足难度目标的nonce。线程数和每个线程的CPU占用比例可以在节点配置中调整，
+// 运行中可以暂停、恢复、停止，并统计算力
 use crate::block;
-use crate::protocol::DIFFICULTY_PREFIX;
-use block::Block;
+use crate::difficulty;
+use block::BlockHeader;
 use rayon::prelude::*;
-use sha2::{Digest, Sha256};
-use std::sync::atomic::{AtomicBool, Ordering};
-// 接受&[u8]作参数的函数其实能够传&Vec<u8>过去
-
-// 最终通过计算得到hash，并把计算hash过程中得到的nonce返回
-pub fn pow_v2(block: Block, atomicbool: &AtomicBool) -> (u128, bool) {
-    let s = format!(
-        "{}{:?}{}{:?}",
-        block.height, block.previous_hash, block.timestamp, block.merkle_root
-    );
-
-    let nonce = (0..u128::MAX)
-        .into_par_iter()
-        .find_any(|n| {
-            if !atomicbool.load(Ordering::Relaxed) {
-                // 外界不准继续计算，直接停止计算
-                return true;
-            }
-            let hash = hash_add_n(&s, *n);
-            &hash[..DIFFICULTY_PREFIX.len()] == DIFFICULTY_PREFIX // Vec<u8>和&[u8]的关系
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// 每个线程每算这么多个nonce检查一次要不要停下来，并按CPU占用比例休息一会儿
+const BATCH_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MiningConfig {
+    pub threads: usize,  // 挖矿线程数，0表示和CPU核数一样
+    pub cpu_percent: u8, // 每个挖矿线程最多占用一个核的百分之多少，1到100
+}
+
+impl Default for MiningConfig {
+    fn default() -> Self {
+        MiningConfig {
+            threads: 0,
+            cpu_percent: 100,
+        }
+    }
+}
+
+impl MiningConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.cpu_percent) {
+            return Err("mining.cpu_percent must be between 1 and 100".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinerState {
+    Running,
+    Paused,
+    Stopped, // 停止之后不能再恢复
+}
+
+pub struct MiningEngine {
+    pool: rayon::ThreadPool,
+    cpu_percent: u8,
+    state: Mutex<MinerState>,
+    state_changed: Condvar,
+    // 每次暂停、停止都加一，正在算的那一轮发现它变了就放弃
+    round: AtomicU64,
+    hashes: AtomicU64, // 一共算了多少次哈希
+    last_sample: Mutex<(Instant, u64)>,
+}
+
+impl MiningEngine {
+    pub fn new(config: &MiningConfig) -> Self {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .thread_name(|i| format!("miner-{}", i))
+            .build()
+            .expect("can build mining thread pool");
+        MiningEngine {
+            pool,
+            cpu_percent: config.cpu_percent,
+            state: Mutex::new(MinerState::Running),
+            state_changed: Condvar::new(),
+            round: AtomicU64::new(0),
+            hashes: AtomicU64::new(0),
+            last_sample: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    pub fn state(&self) -> MinerState {
+        *self.state.lock().unwrap()
+    }
+
+    // 暂停挖矿，正在算的这一轮作废。返回暂停之前是不是在运行，调用者可以据此决定之后要不要恢复
+    pub fn pause(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let was_running = *state == MinerState::Running;
+        if was_running {
+            *state = MinerState::Paused;
+        }
+        self.round.fetch_add(1, Ordering::SeqCst);
+        was_running
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == MinerState::Paused {
+            *state = MinerState::Running;
+            self.state_changed.notify_all();
+        }
+    }
+
+    pub fn stop(&self) {
+        *self.state.lock().unwrap() = MinerState::Stopped;
+        self.round.fetch_add(1, Ordering::SeqCst);
+        self.state_changed.notify_all();
+    }
+
+    // 暂停时一直阻塞，直到被恢复或者停止。返回false表示已经停止了
+    pub fn wait_until_running(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while *state == MinerState::Paused {
+            state = self.state_changed.wait(state).unwrap();
+        }
+        *state == MinerState::Running
+    }
+
+    // 找一个让块头满足难度目标的nonce。算的过程中被暂停或者停止就返回None
+    pub fn mine(&self, header: BlockHeader) -> Option<u128> {
+        // 先记下轮次再看状态，这样在两者之间被暂停也能发现
+        let round = self.round.load(Ordering::SeqCst);
+        if self.state() != MinerState::Running {
+            return None;
+        }
+        let threads = self.threads() as u128;
+        let found = AtomicBool::new(false);
+        self.pool.install(|| {
+            (0..threads)
+                .into_par_iter()
+                .find_map_any(|first| self.search(header, first, threads, round, &found))
         })
-        .unwrap();
+    }
 
-    let mut flag = true;
-    if !atomicbool.load(Ordering::Relaxed) {
-        flag = false;
-    };
+    // 一个线程的工作：依次尝试first, first+stride, first+2*stride...
+    fn search(
+        &self,
+        header: BlockHeader,
+        first: u128,
+        stride: u128,
+        round: u64,
+        found: &AtomicBool,
+    ) -> Option<u128> {
+        let mut nonce = first;
+        loop {
+            if found.load(Ordering::Relaxed) || self.round.load(Ordering::Relaxed) != round {
+                return None;
+            }
+            let started = Instant::now();
+            for i in 0..BATCH_SIZE {
+                let hash = BlockHeader { nonce, ..header }.hash();
+                if difficulty::hash_meets_target(&hash, header.bits) {
+                    self.hashes.fetch_add(i + 1, Ordering::Relaxed);
+                    found.store(true, Ordering::Relaxed);
+                    return Some(nonce);
+                }
+                nonce += stride;
+            }
+            self.hashes.fetch_add(BATCH_SIZE, Ordering::Relaxed);
+            // 算了t时间就休息t*(100-p)/p，这样平均下来只占用p%的CPU
+            if self.cpu_percent < 100 {
+                let busy = started.elapsed();
+                std::thread::sleep(
+                    busy * (100 - self.cpu_percent as u32) / self.cpu_percent as u32,
+                );
+            }
+        }
+    }
 
-    (nonce, flag)
-    // 如果计算是因为标志位变成了false，说明是外界不允许继续计算下去了，返回值的flag设置为false表明该nonce无效
+    // 上次调用以来的平均算力，单位是次/秒
+    pub fn hash_rate(&self) -> f64 {
+        let mut last_sample = self.last_sample.lock().unwrap();
+        let now = Instant::now();
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        let elapsed = now
+            .duration_since(last_sample.0)
+            .max(Duration::from_millis(1));
+        let rate = (hashes - last_sample.1) as f64 / elapsed.as_secs_f64();
+        *last_sample = (now, hashes);
+        rate
+    }
 }
 
-fn hash_add_n(s: &String, nonce: u128) -> Vec<u8> {
-    let mut hash = Sha256::new();
-    let s = s.as_bytes();
-    hash.update(s);
-    hash.update(format!("{}", nonce));
-    hash.finalize().to_vec()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::regtest_spec;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    fn engine() -> MiningEngine {
+        MiningEngine::new(&MiningConfig {
+            threads: 2,
+            cpu_percent: 100,
+        })
+    }
+
+    // 回归测试网的创世块头，bits为None时用它自己的难度
+    fn header(bits: Option<u32>) -> BlockHeader {
+        let header = regtest_spec().to_block().unwrap().header();
+        BlockHeader {
+            bits: bits.unwrap_or(header.bits),
+            ..header
+        }
+    }
+
+    // 目标值只有0x010000，实际上挖不出来，只能被暂停或者停止打断
+    const IMPOSSIBLE_BITS: u32 = 0x0301_0000;
+
+    #[test]
+    fn pause_reports_whether_it_was_running() {
+        let engine = engine();
+        assert!(engine.pause());
+        assert!(!engine.pause());
+        assert_eq!(engine.state(), MinerState::Paused);
+        engine.resume();
+        assert_eq!(engine.state(), MinerState::Running);
+        engine.stop();
+        assert!(!engine.pause());
+        // 停止之后不能再恢复
+        engine.resume();
+        assert_eq!(engine.state(), MinerState::Stopped);
+        assert!(!engine.wait_until_running());
+        assert_eq!(engine.mine(header(None)), None);
+    }
+
+    #[test]
+    fn wait_until_running_wakes_on_resume_and_stop() {
+        let engine = engine();
+        engine.pause();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| engine.wait_until_running());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            engine.resume();
+            assert!(waiter.join().unwrap());
+
+            engine.pause();
+            let waiter = s.spawn(|| engine.wait_until_running());
+            thread::sleep(Duration::from_millis(50));
+            engine.stop();
+            assert!(!waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn pause_and_stop_end_a_search_with_none() {
+        let engine = engine();
+        thread::scope(|s| {
+            let search = s.spawn(|| engine.mine(header(Some(IMPOSSIBLE_BITS))));
+            thread::sleep(Duration::from_millis(50));
+            engine.pause();
+            assert_eq!(search.join().unwrap(), None);
+
+            engine.resume();
+            let search = s.spawn(|| engine.mine(header(Some(IMPOSSIBLE_BITS))));
+            thread::sleep(Duration::from_millis(50));
+            engine.stop();
+            assert_eq!(search.join().unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn mining_loop_pauses_resumes_and_stops() {
+        let engine = engine();
+        let found = AtomicUsize::new(0);
+        let wait_for_more = |than: usize| {
+            while found.load(Ordering::SeqCst) <= than {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        thread::scope(|s| {
+            // 和节点里的挖矿循环一样：暂停时等着，停止时退出
+            let miner = s.spawn(|| {
+                let header = header(None);
+                while engine.wait_until_running() {
+                    if let Some(nonce) = engine.mine(header) {
+                        let hash = BlockHeader { nonce, ..header }.hash();
+                        assert!(difficulty::hash_meets_target(&hash, header.bits));
+                        found.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+            wait_for_more(0);
+
+            assert!(engine.pause());
+            // 暂停之前已经开始的那一轮可能还会挖出一个，等它结束
+            thread::sleep(Duration::from_millis(50));
+            let paused_at = found.load(Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(found.load(Ordering::SeqCst), paused_at);
+
+            engine.resume();
+            wait_for_more(paused_at);
+            engine.stop();
+            miner.join().unwrap();
+        });
+        assert_eq!(engine.state(), MinerState::Stopped);
+    }
 }
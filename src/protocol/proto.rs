@@ -1,6 +1,44 @@
-// pub const DIFFICULTY_PREFIX: &[u8; 2] = &[0, 0];
-pub const DIFFICULTY_PREFIX: &[u8; 3] = &[0, 0, 0];
-use crate::block::Block;
+// 默认的难度上限，即最容易的目标值，用压缩格式表示(见difficulty.rs)。相当于要求哈希的前3个字节为0。
+// 创世规格中可以另行配置，见genesis.rs
+pub const POW_LIMIT_BITS: u32 = 0x1dff_ffff;
+// 期望的出块间隔，每RETARGET_INTERVAL个块按实际出块时间调整一次难度
+pub const TARGET_BLOCK_TIME_MS: i64 = 10_000;
+pub const RETARGET_INTERVAL: usize = 10;
+
+// 块的时间戳必须大于前MEDIAN_TIME_SPAN个块时间戳的中位数，并且不能比本地时间快MAX_FUTURE_DRIFT_MS以上
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub const MAX_FUTURE_DRIFT_MS: i64 = 2 * 60 * 1000;
+
+// 一个块中最多能打包的条目数(upinfo、transactions和utxo_transactions合计)，以及这些条目加起来的最大字节数
+pub const MAX_BLOCK_ENTRIES: usize = 16;
+pub const MAX_BLOCK_BODY_BYTES: usize = 64 * 1024;
+
+// upinfo的有效期。签名时写进去的过期高度最多比打包它的块高UPINFO_MAX_LIFETIME-1
+pub const UPINFO_MAX_LIFETIME: usize = 100;
+
+// 默认的出块奖励：一开始每块INITIAL_BLOCK_REWARD，每HALVING_INTERVAL个块减半。创世规格中可以另行配置
+pub const INITIAL_BLOCK_REWARD: u64 = 50_0000_0000;
+pub const HALVING_INTERVAL: usize = 210_000;
+
+// 交易池的上限：最多MEMPOOL_MAX_ENTRIES条、MEMPOOL_MAX_BYTES字节。进池MEMPOOL_EXPIRY_BLOCKS个块之后还没上链的就丢掉
+pub const MEMPOOL_MAX_ENTRIES: usize = 10_000;
+pub const MEMPOOL_MAX_BYTES: usize = 16 * 1024 * 1024;
+pub const MEMPOOL_EXPIRY_BLOCKS: usize = 360;
+// 运行中每隔这么多秒把交易池保存一次，退出时也会保存
+pub const MEMPOOL_SAVE_INTERVAL_SECS: u64 = 60;
+
+// 挖矿时每隔这么多秒打印一次算力
+pub const MINING_REPORT_INTERVAL_SECS: u64 = 30;
+
+// 节点发来非法块时会被记惩罚分，累计到这个值之后不再从它同步
+pub const BAN_SCORE_THRESHOLD: u32 = 100;
+
+// 同步时除了高度差之外多请求的块数，用来覆盖双方在最近几个块上的分叉
+pub const REORG_LOOKBACK: usize = 6;
+
+use crate::block::{Block, Hash, SignedEntry};
+use crate::transaction::Transaction;
+use crate::utxo::UtxoTransaction;
 use libp2p::floodsub::Topic;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
@@ -22,8 +60,10 @@ pub enum EventMod {
 pub struct ChainInfo {
     pub peer_id: String,
     pub topic: String,
-    pub genesis_hash: Vec<u8>,
+    pub genesis_hash: Hash,
     pub block_height: usize,
+    #[serde(default)]
+    pub total_work: u128, // 对方主链的累计工作量，同步时以它而不是高度为准
 }
 
 // 向某个Peer请求块
@@ -45,16 +85,73 @@ pub struct ResponseBlock {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NewUPINFO {
     pub upinfo: String,
+    pub expires_at: usize, // 过期高度，和upinfo一起签名，见block.rs中的SignedEntry
+    // 付给矿工的手续费，和upinfo一起签名，交易池按每字节的手续费排序
+    #[serde(default)]
+    pub fee: u64,
     pub signature: Vec<u8>,
     pub public_key: Vec<u8>,
 }
 
+impl From<NewUPINFO> for SignedEntry {
+    fn from(n: NewUPINFO) -> Self {
+        SignedEntry {
+            upinfo: n.upinfo,
+            expires_at: n.expires_at,
+            fee: n.fee,
+            public_key: n.public_key,
+            signature: n.signature,
+        }
+    }
+}
+
+impl From<SignedEntry> for NewUPINFO {
+    fn from(n: SignedEntry) -> Self {
+        NewUPINFO {
+            upinfo: n.upinfo,
+            expires_at: n.expires_at,
+            fee: n.fee,
+            signature: n.signature,
+            public_key: n.public_key,
+        }
+    }
+}
+
+// 交易池的库存公告：矿工把新进池的条目的id广播出去，别的矿工只请求自己没有的那些，
+// 这样条目能在全网的交易池之间传开，又不会把大家都有的条目反复发送
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InventoryAnnounce {
+    pub peer_id: String,
+    pub ids: Vec<Hash>, // 见mempool.rs中的PoolEntry::id
+}
+
+// 向公告的节点请求它交易池中的条目
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestEntries {
+    pub event_mod: EventMod, // 和RequestNewBlocks一样，One中第一个是自己的peerid，第二个是对方的
+    pub ids: Vec<Hash>,
+}
+
+// 回应RequestEntries，按类型分开放，对方没有的id直接略过
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseEntries {
+    pub event_mod: EventMod,
+    pub upinfo: Vec<NewUPINFO>,
+    pub transactions: Vec<Transaction>,
+    pub utxo_transactions: Vec<UtxoTransaction>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageEvent {
     ChainInfo(ChainInfo),
-    RequestNewBlocks(RequestNewBlocks), // 必须携带请求来源PeerID
-    ResponseBlock(ResponseBlock),       // 比如携带向谁回应请求的目标节点的的PeerID
+    RequestNewBlocks(RequestNewBlocks),  // 必须携带请求来源PeerID
+    ResponseBlock(ResponseBlock),        // 比如携带向谁回应请求的目标节点的的PeerID
     NewUPINFO(NewUPINFO), // 比如说，发送内容是，明文，通过私钥加密的明文，以及公钥 这样能够保证不会被篡改
+    NewTransaction(Transaction), // 钱包发来的转账交易，和NewUPINFO一样进交易池
+    NewUtxoTransaction(UtxoTransaction), // UTXO模型的链上用的转账交易
+    InventoryAnnounce(InventoryAnnounce),
+    RequestEntries(RequestEntries),
+    ResponseEntries(ResponseEntries),
     FOO,
 }
 
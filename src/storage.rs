@@ -0,0 +1,223 @@
+// 块的持久化存储。blocks.dat是一个只追加的文件，每条记录的格式为：
+//      [u32 长度(LE)][4字节校验和(sha256前4字节)][serde_json序列化后的Block]
+// 写入中途崩溃时最后一条记录可能是残缺的，打开文件时会做一遍恢复扫描，把残缺的尾巴截掉
+use crate::block::Block;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const RECORD_HEADER_LEN: u64 = 8;
+
+pub struct BlockStore {
+    file: File,
+    len: u64,
+}
+
+impl BlockStore {
+    // 打开(或创建)块文件，返回存储本身以及文件中所有完好的块
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Block>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut blocks = vec![];
+        let mut pos = 0usize;
+        while let Some((block, next)) = decode_record(&data, pos) {
+            blocks.push(block);
+            pos = next;
+        }
+
+        let len = pos as u64;
+        if len < data.len() as u64 {
+            println!(
+                "⚠️块文件尾部有{}字节的残缺记录，已截断",
+                data.len() as u64 - len
+            );
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        Ok((BlockStore { file, len }, blocks))
+    }
+
+    // 只读地读出块文件中所有完好的块，文件不存在时返回空。残缺的尾巴只报告不截断，verify等命令不应该改动磁盘上的数据
+    pub fn read_all(path: impl AsRef<Path>) -> io::Result<Vec<Block>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut blocks = vec![];
+        let mut pos = 0usize;
+        while let Some((block, next)) = decode_record(&data, pos) {
+            blocks.push(block);
+            pos = next;
+        }
+        if pos < data.len() {
+            println!("⚠️块文件尾部有{}字节的残缺记录", data.len() - pos);
+        }
+        Ok(blocks)
+    }
+
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let record = encode_record(block)?;
+        let result = self.write_at_end(&record);
+        if result.is_err() {
+            // 写失败就把可能写了一半的记录去掉，避免后续追加的记录跟在垃圾数据后面
+            let _ = self.file.set_len(self.len);
+            return result;
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    fn write_at_end(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(record)?;
+        self.file.sync_data()
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+pub fn encode_record(block: &Block) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(block)?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(&payload));
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+// 从reader中读出下一条记录，reader刚好读完时返回None。和decode_record不同，残缺记录直接报错
+pub fn read_record(reader: &mut impl Read) -> io::Result<Option<Block>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    if checksum(&payload) != header[4..8] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad record checksum",
+        ));
+    }
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+// 从pos处解出一条记录，记录不完整、校验和不对或者反序列化失败都当作残缺记录
+pub fn decode_record(data: &[u8], pos: usize) -> Option<(Block, usize)> {
+    let header = data.get(pos..pos + RECORD_HEADER_LEN as usize)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let start = pos + RECORD_HEADER_LEN as usize;
+    let payload = data.get(start..start + len)?;
+    if checksum(payload) != header[4..8] {
+        return None;
+    }
+    let block = serde_json::from_slice(payload).ok()?;
+    Some((block, start + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::{extend, keypair, regtest_chain, regtest_spec, temp_dir};
+
+    // 写好n个块的块文件，返回文件路径和写进去的块
+    fn write_blocks(name: &str, n: usize) -> (std::path::PathBuf, Vec<Block>) {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        extend(&mut chain, n - 1, &keypair(1));
+        let blocks = chain.main_chain().to_vec();
+        let dir = temp_dir(name);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blocks.dat");
+        let (mut store, _) = BlockStore::open(&path).unwrap();
+        for block in &blocks {
+            store.append(block).unwrap();
+        }
+        (path, blocks)
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<[u8; 32]> {
+        blocks.iter().map(|b| b.hash()).collect()
+    }
+
+    #[test]
+    fn open_truncates_a_torn_tail() {
+        let (path, blocks) = write_blocks("torn-tail", 3);
+        let good_len = fs::metadata(&path).unwrap().len();
+        // 模拟写到一半崩溃：最后一条记录只写了头和一部分内容
+        let record = encode_record(&blocks[1]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        assert_eq!(BlockStore::read_all(&path).unwrap().len(), 3);
+        let (_, stored) = BlockStore::open(&path).unwrap();
+        assert_eq!(hashes(&stored), hashes(&blocks));
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn open_drops_a_last_record_with_a_bad_checksum() {
+        let (path, blocks) = write_blocks("bad-checksum", 3);
+        let mut data = fs::read(&path).unwrap();
+        let last_len = encode_record(&blocks[2]).unwrap().len();
+        // 改掉最后一条记录的校验和，长度是完整的
+        let at = data.len() - last_len + 4;
+        data[at] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let (_, stored) = BlockStore::open(&path).unwrap();
+        assert_eq!(hashes(&stored), hashes(&blocks[..2]));
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            (data.len() - last_len) as u64
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn append_after_truncation_reads_back() {
+        let (path, blocks) = write_blocks("append-after-truncation", 3);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0x42; 5]).unwrap();
+        drop(file);
+
+        let (mut store, stored) = BlockStore::open(&path).unwrap();
+        assert_eq!(stored.len(), 3);
+        store.append(&blocks[1]).unwrap();
+        drop(store);
+
+        // 新记录紧跟在最后一条完好的记录后面，不会接在垃圾数据后面
+        let (_, stored) = BlockStore::open(&path).unwrap();
+        let mut expected = hashes(&blocks);
+        expected.push(blocks[1].hash());
+        assert_eq!(hashes(&stored), expected);
+        let mut reader = File::open(&path).unwrap();
+        let mut count = 0;
+        while read_record(&mut reader).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
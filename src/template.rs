@@ -0,0 +1,294 @@
+// 区块模板：从交易池中挑出条目，和coinbase一起组装成一个待挖的块。
+// 每个块最多打包多少条、多少字节，以及两次组装之间至少、最多等多久，都可以在节点配置(见config.rs)中调整
+use crate::block::{Block, BlockValidationError, Chain, Coinbase, SignedEntry};
+use crate::ledger::{Ledger, TransactionError};
+use crate::mempool::{Mempool, PoolEntry, Verdict};
+use crate::protocol::{MAX_BLOCK_BODY_BYTES, MAX_BLOCK_ENTRIES};
+use crate::transaction::Transaction;
+use crate::utxo::UtxoTransaction;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// 块体中最小的条目：一个输入、没有输出(全部当作手续费)的UTXO交易，4+(32+4)+4+(4+64)字节。
+// 空内容的upinfo(28+32+64)和账户交易(36+32+32+64)都比它大
+const MIN_ENTRY_BYTES: usize = 112;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplateConfig {
+    pub max_entries: usize,   // 不能超过共识规定的MAX_BLOCK_ENTRIES
+    pub max_bytes: usize,     // 不能超过共识规定的MAX_BLOCK_BODY_BYTES
+    pub min_interval_ms: u64, // 交易池中的条目已经够一个块了也至少等这么久再组装
+    pub max_interval_ms: u64, // 条目不够一个块时最多等这么久，到时间就用现有的条目组装
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            max_entries: MAX_BLOCK_ENTRIES,
+            max_bytes: MAX_BLOCK_BODY_BYTES,
+            min_interval_ms: 0,
+            max_interval_ms: 3000,
+        }
+    }
+}
+
+impl TemplateConfig {
+    // 超出共识规则的配置挖出来的块会被别的节点拒绝，所以启动时就报错
+    pub fn validate(&self) -> Result<(), String> {
+        // 一条也放不下的话池永远是满的，只能挖空块
+        if self.max_entries == 0 {
+            return Err("template.max_entries must be at least 1".to_string());
+        }
+        if self.max_entries > MAX_BLOCK_ENTRIES {
+            return Err(format!(
+                "template.max_entries must not exceed {}",
+                MAX_BLOCK_ENTRIES
+            ));
+        }
+        // 连最小的条目都放不下的话同样只能挖空块
+        if self.max_bytes < MIN_ENTRY_BYTES {
+            return Err(format!(
+                "template.max_bytes must be at least {}",
+                MIN_ENTRY_BYTES
+            ));
+        }
+        if self.max_bytes > MAX_BLOCK_BODY_BYTES {
+            return Err(format!(
+                "template.max_bytes must not exceed {}",
+                MAX_BLOCK_BODY_BYTES
+            ));
+        }
+        if self.min_interval_ms > self.max_interval_ms {
+            return Err("template.min_interval_ms must not exceed max_interval_ms".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_millis(self.min_interval_ms)
+    }
+
+    pub fn max_interval(&self) -> Duration {
+        Duration::from_millis(self.max_interval_ms)
+    }
+
+    // 交易池中的条目是否已经够装满一个块
+    pub fn is_filled_by(&self, mempool: &Mempool) -> bool {
+        mempool.len() >= self.max_entries || mempool.bytes() >= self.max_bytes
+    }
+}
+
+// 在chain的主链末尾组装一个待挖的块，nonce为0。now_millis是本地时间。
+// 按每字节手续费从高到低挑出条目，交易在主链末尾账本的副本上试着执行一遍，能执行成功的才打包。
+// nonce还没轮到的账户交易、输入还不存在的UTXO交易留在池中，其他执行失败的直接从池中删掉
+pub fn assemble(
+    chain: &Chain,
+    mempool: &mut Mempool,
+    config: &TemplateConfig,
+    miner_public_key: &[u8],
+    now_millis: i64,
+) -> Block {
+    let parent = chain.last_block();
+    let height = parent.height + 1;
+    // 时间戳必须比前面几个块的中位数大，本地时钟偏慢时就用中位数+1
+    let timestamp = now_millis.max(chain.median_time_past(parent) + 1);
+    let coinbase = Coinbase {
+        public_key: miner_public_key.to_vec(),
+        reward: chain.block_reward(height),
+    };
+    // 最近的块里已经有了的upinfo不能再打包，防止同一个签名被反复上链
+    let mut seen = chain.recent_entries(parent);
+    let mut ledger = chain.ledger().clone();
+
+    if mempool.is_empty() {
+        println!("交易池是空的，挖只有coinbase的空块");
+    } else {
+        println!(
+            "开始挑选交易,当前交易池中有{}条，共{}字节",
+            mempool.len(),
+            mempool.bytes()
+        );
+    }
+    let template = mempool.build_template(config.max_entries, config.max_bytes, |entry| {
+        let result = match (entry, &mut ledger) {
+            (PoolEntry::Upinfo(n), ledger) => {
+                let n = SignedEntry::from(n.clone());
+                if !n.is_live_at(height) {
+                    println!("丢弃一条已过期的上链请求");
+                    return Verdict::Reject;
+                }
+                if seen.contains(&n.replay_id()) {
+                    println!("丢弃一条已经上链的上链请求");
+                    return Verdict::Reject;
+                }
+                let charged = match ledger {
+                    Ledger::Account(ledger) => ledger.charge_upinfo_fee(&n),
+                    Ledger::Utxo(_) if n.fee > 0 => Err(TransactionError::WrongModel),
+                    Ledger::Utxo(_) => Ok(()),
+                };
+                if charged.is_ok() {
+                    seen.insert(n.replay_id());
+                }
+                charged
+            }
+            (PoolEntry::Transaction(n), Ledger::Account(ledger)) => ledger.apply_transaction(n),
+            (PoolEntry::UtxoTransaction(n), Ledger::Utxo(ledger)) => {
+                ledger.apply_transaction(n).map(|_| ())
+            }
+            _ => Err(TransactionError::WrongModel),
+        };
+        match result {
+            Ok(()) => Verdict::Include,
+            Err(TransactionError::BadNonce { expected, got }) if got > expected => Verdict::Skip,
+            Err(TransactionError::UnknownInput { .. }) => Verdict::Skip,
+            Err(e) => {
+                println!("丢弃一笔交易:{}", e);
+                Verdict::Reject
+            }
+        }
+    });
+
+    let mut upinfo: Vec<SignedEntry> = vec![];
+    let mut transactions: Vec<Transaction> = vec![];
+    let mut utxo_transactions: Vec<UtxoTransaction> = vec![];
+    for entry in template {
+        match entry {
+            PoolEntry::Upinfo(n) => upinfo.push(n.into()),
+            PoolEntry::Transaction(n) => transactions.push(n),
+            PoolEntry::UtxoTransaction(n) => utxo_transactions.push(n),
+        }
+    }
+
+    // coinbase是默克尔树的第一个叶子，没有upinfo和交易时就是只有coinbase的空块
+    let mut block = Block {
+        height,
+        previous_hash: chain.tip_hash(),
+        timestamp,
+        merkle_root: [0; 32],
+        bits: chain.next_bits(parent),
+        nonce: 0,
+        coinbase,
+        upinfo,
+        transactions,
+        utxo_transactions,
+    };
+    block.merkle_root = block.compute_merkle_root();
+
+    // 上面是按手续费交错挑选的，而块中先扣全部upinfo的手续费再执行交易，个别情况下(比如upinfo的手续费
+    // 要靠同一个块中别人转来的钱付)按块的顺序执行不下去。这时把出错的条目拿掉再试，它们留在池中等下一个块
+    loop {
+        let mut ledger = chain.ledger().clone();
+        match ledger.apply_block(&block) {
+            Err(BlockValidationError::BadUpinfoFee { index, .. }) => {
+                block.upinfo.remove(index);
+            }
+            Err(BlockValidationError::BadTransaction { index, .. })
+                if index < block.transactions.len() =>
+            {
+                block.transactions.remove(index);
+            }
+            Err(BlockValidationError::BadTransaction { index, .. })
+                if index < block.utxo_transactions.len() =>
+            {
+                block.utxo_transactions.remove(index);
+            }
+            _ => break,
+        }
+        block.merkle_root = block.compute_merkle_root();
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::*;
+    use crate::utxo::OutPoint;
+
+    #[test]
+    fn validate_rejects_out_of_range_limits() {
+        assert!(TemplateConfig::default().validate().is_ok());
+        let zero = TemplateConfig {
+            max_entries: 0,
+            ..TemplateConfig::default()
+        };
+        assert!(zero.validate().is_err());
+        let too_many = TemplateConfig {
+            max_entries: MAX_BLOCK_ENTRIES + 1,
+            ..TemplateConfig::default()
+        };
+        assert!(too_many.validate().is_err());
+        for max_bytes in [0, MIN_ENTRY_BYTES - 1, MAX_BLOCK_BODY_BYTES + 1] {
+            let config = TemplateConfig {
+                max_bytes,
+                ..TemplateConfig::default()
+            };
+            assert!(config.validate().is_err(), "max_bytes {}", max_bytes);
+        }
+        let smallest = TemplateConfig {
+            max_bytes: MIN_ENTRY_BYTES,
+            ..TemplateConfig::default()
+        };
+        assert!(smallest.validate().is_ok());
+    }
+
+    #[test]
+    fn min_entry_bytes_is_the_smallest_entry() {
+        let (chain, _) = regtest_chain(&utxo_regtest_spec());
+        let chain_id = chain.chain_id();
+        let outpoint = OutPoint {
+            txid: chain.last_block().hash(),
+            index: 0,
+        };
+        let burn = UtxoTransaction::new_signed(vec![outpoint], vec![], &chain_id, &keypair(1));
+        assert_eq!(burn.encoded_len(), MIN_ENTRY_BYTES);
+        let empty = SignedEntry::new_signed(String::new(), 1, 0, &chain_id, &keypair(1));
+        assert!(empty.encoded_len() > MIN_ENTRY_BYTES);
+        let transfer = Transaction::new_signed(vec![0; 32], 1, 0, 0, &chain_id, &keypair(1));
+        assert!(transfer.encoded_len() > MIN_ENTRY_BYTES);
+    }
+
+    #[test]
+    fn entries_that_only_fit_in_pool_order_are_left_out() {
+        let (mut chain, _) = regtest_chain(&regtest_spec());
+        let (alice, bob, carol) = (keypair(1), keypair(2), keypair(3));
+        extend(&mut chain, 1, &alice);
+        extend(&mut chain, 1, &bob);
+        let reward = chain.block_reward(2);
+        let chain_id = chain.chain_id();
+        let to = |n: &ed25519_dalek::Keypair| n.public.to_bytes().to_vec();
+
+        // 按池中的顺序：bob先花光，alice再转给bob，bob再用这笔钱付upinfo的手续费。
+        // 块中upinfo的手续费先扣，bob就付不起第一笔交易了
+        let spend_all = Transaction::new_signed(to(&carol), reward - 1, 1, 0, &chain_id, &bob);
+        let refill = Transaction::new_signed(to(&bob), 5, 1, 0, &chain_id, &alice);
+        let expires_at = chain.last_block().height + 10;
+        let upinfo = SignedEntry::new_signed("late".to_string(), expires_at, 1, &chain_id, &bob);
+        let mut mempool = Mempool::new(10, 1 << 20, 10);
+        mempool
+            .insert(PoolEntry::Transaction(spend_all), 100, 2)
+            .unwrap();
+        mempool
+            .insert(PoolEntry::Transaction(refill.clone()), 50, 2)
+            .unwrap();
+        mempool
+            .insert(PoolEntry::Upinfo(upinfo.into()), 1, 2)
+            .unwrap();
+
+        let now = chain.last_block().timestamp + 1;
+        let block = assemble(
+            &chain,
+            &mut mempool,
+            &TemplateConfig::default(),
+            &to(&carol),
+            now,
+        );
+        assert_eq!(block.transactions, vec![refill]);
+        assert_eq!(block.upinfo.len(), 1);
+        assert_eq!(block.merkle_root, block.compute_merkle_root());
+        chain.ledger().clone().apply_block(&block).unwrap();
+        // 被拿掉的交易还留在池中
+        assert_eq!(mempool.len(), 3);
+    }
+}
@@ -0,0 +1,84 @@
+// 转账交易。from和to都是ed25519公钥，交易由from的私钥签名。
+// nonce是from账户之前已经上链的交易数，同一个nonce只能用一次，这样同一笔交易就不能被重放
+use crate::block::Hash;
+use crate::cryptography::{self, ChainId, MessageTag};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Transaction {
+    pub from: Vec<u8>,
+    pub to: Vec<u8>,
+    pub amount: u64,
+    pub fee: u64, // 付给打包这笔交易的矿工
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Transaction {
+    // 钱包用这个生成由keypair签名的转账交易，from就是keypair的公钥
+    pub fn new_signed(
+        to: Vec<u8>,
+        amount: u64,
+        fee: u64,
+        nonce: u64,
+        chain_id: &ChainId,
+        keypair: &ed25519_dalek::Keypair,
+    ) -> Self {
+        let mut tx = Transaction {
+            from: keypair.public.to_bytes().to_vec(),
+            to,
+            amount,
+            fee,
+            nonce,
+            signature: vec![],
+        };
+        tx.signature = cryptography::sign(
+            chain_id,
+            MessageTag::Transaction,
+            &tx.signing_bytes(),
+            keypair,
+        );
+        tx
+    }
+
+    // 签名的原文：from、to各带4字节大端序长度，后面依次是amount、fee、nonce，都是8字节大端序
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + self.from.len() + self.to.len());
+        for field in [&self.from, &self.to] {
+            buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            buf.extend_from_slice(field);
+        }
+        buf.extend_from_slice(&self.amount.to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_be_bytes());
+        buf.extend_from_slice(&self.nonce.to_be_bytes());
+        buf
+    }
+
+    // 默克尔树叶子的原像：签名原文后面再跟上带长度的签名
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.signing_bytes();
+        buf.extend_from_slice(&(self.signature.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.signature);
+        buf
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        36 + self.from.len() + self.to.len() + self.signature.len()
+    }
+
+    // 交易id，同时也是它在默克尔树中的叶子
+    pub fn hash(&self) -> Hash {
+        Sha256::digest(self.encode()).into()
+    }
+
+    pub fn verify(&self, chain_id: &ChainId) -> bool {
+        cryptography::verify(
+            &self.from,
+            chain_id,
+            MessageTag::Transaction,
+            &self.signing_bytes(),
+            &self.signature,
+        )
+    }
+}
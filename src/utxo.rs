@@ -0,0 +1,488 @@
+// UTXO模型的交易和账本，是账户模型(ledger.rs中的AccountLedger)之外的另一种选择，用哪一种由创世规格决定。
+// 交易花掉之前的输出(UTXO)并产生新的输出，输入金额减去输出金额就是给矿工的手续费。
+// 每个块的coinbase也会产生一个输出，它的txid是块哈希，下标为0
+use crate::block::{Block, Hash};
+use crate::cryptography::{self, ChainId, MessageTag};
+use crate::ledger::TransactionError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub index: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub signature: Vec<u8>, // 被花掉的输出的主人对整笔交易的签名
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TxOut {
+    pub public_key: Vec<u8>,
+    pub amount: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+}
+
+impl UtxoTransaction {
+    // 钱包用这个生成花掉自己输出的交易。inputs引用的输出都必须属于keypair，每个输入带着同一个签名
+    pub fn new_signed(
+        inputs: Vec<OutPoint>,
+        outputs: Vec<TxOut>,
+        chain_id: &ChainId,
+        keypair: &ed25519_dalek::Keypair,
+    ) -> Self {
+        let mut tx = UtxoTransaction {
+            inputs: inputs
+                .into_iter()
+                .map(|n| TxIn {
+                    previous_output: n,
+                    signature: vec![],
+                })
+                .collect(),
+            outputs,
+        };
+        let signature = cryptography::sign(
+            chain_id,
+            MessageTag::UtxoTransaction,
+            &tx.signing_bytes(),
+            keypair,
+        );
+        for input in tx.inputs.iter_mut() {
+            input.signature = signature.clone();
+        }
+        tx
+    }
+
+    // 签名的原文，不包含签名本身：
+    //      输入个数(4) | 每个输入的txid(32)和index(4) | 输出个数(4) | 每个输出带4字节长度的public_key和amount(8)
+    // 整数一律大端序
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in self.inputs.iter() {
+            buf.extend_from_slice(&input.previous_output.txid);
+            buf.extend_from_slice(&input.previous_output.index.to_be_bytes());
+        }
+        buf.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in self.outputs.iter() {
+            buf.extend_from_slice(&(output.public_key.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&output.public_key);
+            buf.extend_from_slice(&output.amount.to_be_bytes());
+        }
+        buf
+    }
+
+    // 默克尔树叶子的原像：签名原文后面依次跟上每个输入带长度的签名
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = self.signing_bytes();
+        for input in self.inputs.iter() {
+            buf.extend_from_slice(&(input.signature.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&input.signature);
+        }
+        buf
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.encode().len()
+    }
+
+    // 交易id不包含签名，改动签名不会改变txid，后面引用它的交易也就不会失效
+    pub fn txid(&self) -> Hash {
+        Sha256::digest(self.signing_bytes()).into()
+    }
+
+    // 默克尔树中的叶子，签名也要被块头承诺
+    pub fn leaf_hash(&self) -> Hash {
+        Sha256::digest(self.encode()).into()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UtxoLedger {
+    chain_id: ChainId, // 验证输入的签名时要用
+    outputs: HashMap<OutPoint, TxOut>,
+    // 每个已执行的块中每笔交易花掉了哪些输出，回滚时要把它们放回来
+    spent: HashMap<Hash, Vec<Vec<(OutPoint, TxOut)>>>,
+}
+
+// UTXO集合的快照，outputs按OutPoint排序，同样的UTXO集合得到的快照完全一样
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UtxoSnapshot {
+    pub height: usize,
+    pub tip_hash: Hash,
+    pub outputs: Vec<(OutPoint, TxOut)>,
+}
+
+impl UtxoLedger {
+    pub fn new(chain_id: ChainId) -> Self {
+        UtxoLedger {
+            chain_id,
+            outputs: HashMap::new(),
+            spent: HashMap::new(),
+        }
+    }
+
+    pub fn balance(&self, public_key: &[u8]) -> u64 {
+        self.outputs
+            .values()
+            .filter(|n| n.public_key == public_key)
+            .map(|n| n.amount)
+            .sum()
+    }
+
+    pub fn output(&self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.outputs.get(outpoint).cloned()
+    }
+
+    // 属于public_key的所有输出，按OutPoint排序，钱包挑选输入时用
+    pub fn outputs_of(&self, public_key: &[u8]) -> Vec<(OutPoint, TxOut)> {
+        let mut outputs: Vec<(OutPoint, TxOut)> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| output.public_key == public_key)
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect();
+        outputs.sort_by_key(|n| n.0);
+        outputs
+    }
+
+    // 检查通过之后花掉输入、加入输出，返回手续费。失败时账本不变
+    pub fn apply_transaction(&mut self, tx: &UtxoTransaction) -> Result<u64, TransactionError> {
+        let fee = self.check_transaction(tx)?;
+        self.spend(tx);
+        Ok(fee)
+    }
+
+    // 每个输入都必须是还没花掉的输出，并且带着这个输出的主人的签名；输出的总额不能超过输入的总额
+    pub fn check_transaction(&self, tx: &UtxoTransaction) -> Result<u64, TransactionError> {
+        self.check_transaction_with(tx, |n| self.output(n))
+    }
+
+    // 和check_transaction一样，只是输入引用的输出由lookup查找，交易池用它把池中交易的输出也算进来
+    pub fn check_transaction_with(
+        &self,
+        tx: &UtxoTransaction,
+        lookup: impl Fn(&OutPoint) -> Option<TxOut>,
+    ) -> Result<u64, TransactionError> {
+        if tx.inputs.is_empty() {
+            return Err(TransactionError::NoInputs);
+        }
+        let message = tx.signing_bytes();
+        let mut seen = HashSet::new();
+        let mut input_sum: u64 = 0;
+        for (i, input) in tx.inputs.iter().enumerate() {
+            if !seen.insert(input.previous_output) {
+                return Err(TransactionError::DoubleSpend { input: i });
+            }
+            let spent = lookup(&input.previous_output)
+                .ok_or(TransactionError::UnknownInput { input: i })?;
+            if !cryptography::verify(
+                &spent.public_key,
+                &self.chain_id,
+                MessageTag::UtxoTransaction,
+                &message,
+                &input.signature,
+            ) {
+                return Err(TransactionError::BadSignature);
+            }
+            input_sum = input_sum
+                .checked_add(spent.amount)
+                .ok_or(TransactionError::Overflow)?;
+        }
+        let output_sum = tx
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, n| sum.checked_add(n.amount))
+            .ok_or(TransactionError::Overflow)?;
+        if output_sum > input_sum {
+            return Err(TransactionError::InsufficientBalance {
+                balance: input_sum,
+                needed: output_sum,
+            });
+        }
+        Ok(input_sum - output_sum)
+    }
+
+    // 返回花掉的输出，回滚时用
+    fn spend(&mut self, tx: &UtxoTransaction) -> Vec<(OutPoint, TxOut)> {
+        let spent = tx
+            .inputs
+            .iter()
+            .map(|n| {
+                let output = self.outputs.remove(&n.previous_output).unwrap();
+                (n.previous_output, output)
+            })
+            .collect();
+        let txid = tx.txid();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint {
+                txid,
+                index: index as u32,
+            };
+            self.outputs.insert(outpoint, output.clone());
+        }
+        spent
+    }
+
+    fn unspend(&mut self, tx: &UtxoTransaction, spent: Vec<(OutPoint, TxOut)>) {
+        let txid = tx.txid();
+        for index in 0..tx.outputs.len() {
+            self.outputs.remove(&OutPoint {
+                txid,
+                index: index as u32,
+            });
+        }
+        self.outputs.extend(spent);
+    }
+
+    // 依次执行块中的交易，最后加入coinbase的输出。要么整个块都生效，要么账本不变，出错时返回出错交易的下标。
+    // 同一个块中的交易可以花前面交易的输出，但同一个输出只能被花一次
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), (usize, TransactionError)> {
+        if !block.transactions.is_empty() {
+            return Err((0, TransactionError::WrongModel));
+        }
+
+        // 先整体检查一遍，块中任意两个输入都不能花同一个输出
+        let mut seen = HashSet::new();
+        for (i, tx) in block.utxo_transactions.iter().enumerate() {
+            for (input, n) in tx.inputs.iter().enumerate() {
+                if !seen.insert(n.previous_output) {
+                    return Err((i, TransactionError::DoubleSpend { input }));
+                }
+            }
+        }
+
+        let mut spent = vec![];
+        let mut fees: u64 = 0;
+        for (i, tx) in block.utxo_transactions.iter().enumerate() {
+            let result = self
+                .check_transaction(tx)
+                .and_then(|fee| fees.checked_add(fee).ok_or(TransactionError::Overflow));
+            match result {
+                Ok(total) => {
+                    fees = total;
+                    spent.push(self.spend(tx));
+                }
+                Err(e) => {
+                    self.revert(&block.utxo_transactions[..i], spent);
+                    return Err((i, e));
+                }
+            }
+        }
+
+        let miner_income = match block.coinbase.reward.checked_add(fees) {
+            Some(n) => n,
+            None => {
+                self.revert(&block.utxo_transactions, spent);
+                return Err((block.utxo_transactions.len(), TransactionError::Overflow));
+            }
+        };
+        let block_hash = block.hash();
+        if miner_income > 0 {
+            self.outputs.insert(
+                OutPoint {
+                    txid: block_hash,
+                    index: 0,
+                },
+                TxOut {
+                    public_key: block.coinbase.public_key.clone(),
+                    amount: miner_income,
+                },
+            );
+        }
+        self.spent.insert(block_hash, spent);
+        Ok(())
+    }
+
+    // 撤销一个已经apply过的块，必须从主链末尾开始一个一个往回撤
+    pub fn rollback_block(&mut self, block: &Block) {
+        let block_hash = block.hash();
+        self.outputs.remove(&OutPoint {
+            txid: block_hash,
+            index: 0,
+        });
+        let spent = self.spent.remove(&block_hash).unwrap();
+        self.revert(&block.utxo_transactions, spent);
+    }
+
+    // 按相反的顺序撤销transactions，spent是它们各自花掉的输出
+    fn revert(&mut self, transactions: &[UtxoTransaction], spent: Vec<Vec<(OutPoint, TxOut)>>) {
+        for (tx, spent) in transactions.iter().zip(spent).rev() {
+            self.unspend(tx, spent);
+        }
+    }
+
+    pub fn snapshot(&self, height: usize, tip_hash: Hash) -> UtxoSnapshot {
+        let mut outputs: Vec<(OutPoint, TxOut)> = self
+            .outputs
+            .iter()
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect();
+        outputs.sort_by_key(|n| n.0);
+        UtxoSnapshot {
+            height,
+            tip_hash,
+            outputs,
+        }
+    }
+
+    // 从快照恢复出来的账本没有回滚信息，不能再撤回快照之前的块。快照中有重复的输出时返回None
+    pub fn restore(snapshot: &UtxoSnapshot, chain_id: ChainId) -> Option<Self> {
+        let mut outputs = HashMap::new();
+        for (outpoint, output) in snapshot.outputs.iter() {
+            if outputs.insert(*outpoint, output.clone()).is_some() {
+                return None;
+            }
+        }
+        Some(UtxoLedger {
+            chain_id,
+            outputs,
+            spent: HashMap::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::testing::*;
+    use crate::block::Chain;
+    use ed25519_dalek::Keypair;
+
+    fn pay(to: &Keypair, amount: u64) -> TxOut {
+        TxOut {
+            public_key: to.public.to_bytes().to_vec(),
+            amount,
+        }
+    }
+
+    // 把owner的全部输出转给to，手续费为1，找零给自己
+    fn spend_all(
+        chain: &Chain,
+        ledger: &UtxoLedger,
+        owner: &Keypair,
+        to: &Keypair,
+        amount: u64,
+    ) -> UtxoTransaction {
+        let own = ledger.outputs_of(owner.public.as_bytes());
+        let total: u64 = own.iter().map(|n| n.1.amount).sum();
+        let inputs = own.into_iter().map(|n| n.0).collect();
+        let outputs = vec![pay(to, amount), pay(owner, total - amount - 1)];
+        UtxoTransaction::new_signed(inputs, outputs, &chain.chain_id(), owner)
+    }
+
+    #[test]
+    fn signed_transfer_spends_and_pays_change() {
+        let (chain, mut ledger) = funded::<UtxoLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let reward = chain.block_reward(1);
+        let tx = spend_all(&chain, &ledger, &alice, &bob, 10);
+        assert_eq!(ledger.apply_transaction(&tx), Ok(1));
+        assert_eq!(ledger.balance(bob.public.as_bytes()), 10);
+        assert_eq!(ledger.balance(alice.public.as_bytes()), reward - 11);
+        // 原来的coinbase输出已经被花掉了
+        assert_eq!(
+            ledger.apply_transaction(&tx),
+            Err(TransactionError::UnknownInput { input: 0 })
+        );
+    }
+
+    #[test]
+    fn only_the_owner_can_spend() {
+        let (chain, ledger) = funded::<UtxoLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let inputs = ledger
+            .outputs_of(alice.public.as_bytes())
+            .into_iter()
+            .map(|n| n.0)
+            .collect();
+        let tx = UtxoTransaction::new_signed(inputs, vec![pay(&bob, 10)], &chain.chain_id(), &bob);
+        assert_eq!(
+            ledger.check_transaction(&tx),
+            Err(TransactionError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn double_spends_are_rejected() {
+        let (chain, mut ledger) = funded::<UtxoLedger>();
+        let (alice, bob) = (keypair(1), keypair(2));
+        let coin = ledger.outputs_of(alice.public.as_bytes())[0].0;
+
+        let twice = UtxoTransaction::new_signed(
+            vec![coin, coin],
+            vec![pay(&bob, 10)],
+            &chain.chain_id(),
+            &alice,
+        );
+        assert_eq!(
+            ledger.check_transaction(&twice),
+            Err(TransactionError::DoubleSpend { input: 1 })
+        );
+
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &alice);
+        block.utxo_transactions = vec![
+            UtxoTransaction::new_signed(vec![coin], vec![pay(&bob, 10)], &chain.chain_id(), &alice),
+            UtxoTransaction::new_signed(vec![coin], vec![pay(&bob, 20)], &chain.chain_id(), &alice),
+        ];
+        let before = ledger.snapshot(1, chain.tip_hash());
+        assert_eq!(
+            ledger.apply_block(&block),
+            Err((1, TransactionError::DoubleSpend { input: 0 }))
+        );
+        assert_eq!(ledger.snapshot(1, chain.tip_hash()), before);
+    }
+
+    #[test]
+    fn rollback_restores_the_utxo_set() {
+        let (chain, mut ledger) = funded::<UtxoLedger>();
+        let (alice, bob, miner) = (keypair(1), keypair(2), keypair(3));
+        let parent = chain.last_block().clone();
+        let mut block = child_of(&chain, &parent, parent.timestamp + 1, &miner);
+        let first = spend_all(&chain, &ledger, &alice, &bob, 10);
+        // 同一个块中后面的交易可以花前面交易的输出
+        let second = UtxoTransaction::new_signed(
+            vec![OutPoint {
+                txid: first.txid(),
+                index: 0,
+            }],
+            vec![pay(&alice, 9)],
+            &chain.chain_id(),
+            &bob,
+        );
+        block.utxo_transactions = vec![first, second];
+        let before = ledger.snapshot(1, chain.tip_hash());
+
+        ledger.apply_block(&block).unwrap();
+        assert_eq!(ledger.balance(bob.public.as_bytes()), 0);
+        assert_eq!(
+            ledger.balance(miner.public.as_bytes()),
+            block.coinbase.reward + 2
+        );
+        ledger.rollback_block(&block);
+        assert_eq!(ledger.snapshot(1, chain.tip_hash()), before);
+    }
+
+    #[test]
+    fn snapshot_restores_to_the_same_set() {
+        let (chain, ledger) = funded::<UtxoLedger>();
+        let snapshot = ledger.snapshot(1, chain.tip_hash());
+        assert_eq!(snapshot.outputs.len(), 1);
+        let restored = UtxoLedger::restore(&snapshot, chain.chain_id()).unwrap();
+        assert_eq!(restored.snapshot(1, chain.tip_hash()), snapshot);
+
+        let mut duplicated = snapshot.clone();
+        duplicated.outputs.push(snapshot.outputs[0].clone());
+        assert!(UtxoLedger::restore(&duplicated, chain.chain_id()).is_none());
+    }
+}